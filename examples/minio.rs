@@ -7,29 +7,17 @@
 //! 4. you have setup the access key and secret key for minio and saved it under ~/.aws/credentials.
 //!
 //! Run with:
-//!     cargo run --example   minio --features aws
+//!     AWS_ENDPOINT=http://localhost:9000 AWS_ALLOW_HTTP=true cargo run --example   minio --features aws
 
 use obstacle::ObstacleError;
 
 #[cfg(feature = "aws")]
 fn mmap_from_url() -> Result<(), ObstacleError> {
-    use awscreds::Credentials;
-    use object_store::ClientConfigKey;
     use obstacle::set_cloud_options;
-    use obstacle::AmazonS3ConfigKey as Key;
     use std::str::from_utf8;
 
-    let cred = Credentials::default().unwrap();
-
-    // Propagate the credentials and other cloud options.
-    let cloud_options = obstacle::CloudOptions::default().with_aws([
-        (Key::AccessKeyId, &cred.access_key.unwrap()),
-        (Key::SecretAccessKey, &cred.secret_key.unwrap()),
-        (Key::Region, &"us-east-1a".into()),
-        (Key::Client(ClientConfigKey::AllowHttp), &"true".into()),
-        (Key::Endpoint, &"http://localhost:9000".into()),
-    ]);
-    set_cloud_options(cloud_options);
+    // Read the credentials from the profile and the minio endpoint from the environment.
+    set_cloud_options(obstacle::CloudOptions::from_env());
     let mmaped = obstacle::Mmap::from_url(&"s3://one/foods2.csv")?.unwrap();
     print!("content: {}.", from_utf8(&mmaped[..]).unwrap());
     Ok(())
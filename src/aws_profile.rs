@@ -0,0 +1,180 @@
+//! Read the AWS profiles saved by the AWS command line tools.
+//!
+//! The profiles are read from `~/.aws/config`, sections named `[profile <name>]` or `[default]`, and from
+//! `~/.aws/credentials`, sections named `[<name>]`, the credentials take precedence. The locations can be changed
+//! with the `AWS_CONFIG_FILE` and `AWS_SHARED_CREDENTIALS_FILE` environment variables, like for the AWS tools.
+//!
+//! Only the static credentials, the region and the endpoint are supported, other settings are ignored.
+
+use crate::err::ObstacleError;
+use home::home_dir;
+use object_store::aws::AmazonS3ConfigKey;
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// The profile used when `AWS_PROFILE` is not set.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// The configuration key for a setting of a profile.
+fn _config_key(name: &str) -> Option<AmazonS3ConfigKey> {
+    Some(match name {
+        "aws_access_key_id" => AmazonS3ConfigKey::AccessKeyId,
+        "aws_secret_access_key" => AmazonS3ConfigKey::SecretAccessKey,
+        "aws_session_token" => AmazonS3ConfigKey::Token,
+        "region" => AmazonS3ConfigKey::Region,
+        "endpoint_url" => AmazonS3ConfigKey::Endpoint,
+        _ => return None,
+    })
+}
+
+/// The key value pairs of a section of an ini file, None when the section is missing.
+fn _parse_section(content: &str, section: &str) -> Option<Vec<(String, String)>> {
+    let mut found = false;
+    let mut current = false;
+    let mut values = vec![];
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            current = name.trim() == section;
+            found |= current;
+            continue;
+        }
+        if let (true, Some((key, value))) = (current, line.split_once('=')) {
+            values.push((key.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    found.then_some(values)
+}
+
+/// The settings of the profile found in the content of the config and the credentials files.
+fn _profile_configs(
+    config: Option<&str>,
+    credentials: Option<&str>,
+    profile: &str,
+) -> Option<Vec<(AmazonS3ConfigKey, String)>> {
+    let config_section = match profile {
+        DEFAULT_PROFILE => DEFAULT_PROFILE.to_string(),
+        _ => format!("profile {}", profile),
+    };
+    let config = config.and_then(|content| _parse_section(content, &config_section));
+    let credentials = credentials.and_then(|content| _parse_section(content, profile));
+    if config.is_none() && credentials.is_none() {
+        return None;
+    }
+    Some(
+        config
+            .into_iter()
+            .chain(credentials)
+            .flatten()
+            .filter_map(|(key, value)| Some((_config_key(&key)?, value)))
+            .collect(),
+    )
+}
+
+/// The location of a profile file, from the environment variable or under `~/.aws`.
+fn _profile_file(env_key: &str, file_name: &str) -> Option<PathBuf> {
+    match env::var_os(env_key) {
+        Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => home_dir().map(|home| home.join(".aws").join(file_name)),
+    }
+}
+
+fn _read_optional(path: Option<PathBuf>) -> Result<Option<String>, ObstacleError> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The name of the profile selected with `AWS_PROFILE`, `default` otherwise.
+pub fn selected_profile() -> String {
+    env::var("AWS_PROFILE")
+        .ok()
+        .filter(|profile| !profile.is_empty())
+        .unwrap_or_else(|| DEFAULT_PROFILE.to_string())
+}
+
+/// Read the settings of the profile, None when neither file defines the profile.
+pub fn read_profile(
+    profile: &str,
+) -> Result<Option<Vec<(AmazonS3ConfigKey, String)>>, ObstacleError> {
+    let config = _read_optional(_profile_file("AWS_CONFIG_FILE", "config"))?;
+    let credentials = _read_optional(_profile_file("AWS_SHARED_CREDENTIALS_FILE", "credentials"))?;
+    Ok(_profile_configs(
+        config.as_deref(),
+        credentials.as_deref(),
+        profile,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: &str = "
+[default]
+region = us-east-1
+
+[profile minio]
+region = us-east-1a
+endpoint_url = http://localhost:9000
+output = json
+";
+
+    const CREDENTIALS: &str = "
+# Comments are ignored.
+[default]
+aws_access_key_id = AKIDEFAULT
+aws_secret_access_key = secret
+
+[minio]
+aws_access_key_id=minioadmin
+aws_secret_access_key=minioadmin
+region = eu-west-1
+";
+
+    #[test]
+    fn test_profile_configs() {
+        assert_eq!(
+            _profile_configs(Some(CONFIG), Some(CREDENTIALS), "default"),
+            Some(vec![
+                (AmazonS3ConfigKey::Region, "us-east-1".into()),
+                (AmazonS3ConfigKey::AccessKeyId, "AKIDEFAULT".into()),
+                (AmazonS3ConfigKey::SecretAccessKey, "secret".into()),
+            ])
+        );
+        // The credentials come last, they take precedence.
+        assert_eq!(
+            _profile_configs(Some(CONFIG), Some(CREDENTIALS), "minio"),
+            Some(vec![
+                (AmazonS3ConfigKey::Region, "us-east-1a".into()),
+                (AmazonS3ConfigKey::Endpoint, "http://localhost:9000".into()),
+                (AmazonS3ConfigKey::AccessKeyId, "minioadmin".into()),
+                (AmazonS3ConfigKey::SecretAccessKey, "minioadmin".into()),
+                (AmazonS3ConfigKey::Region, "eu-west-1".into()),
+            ])
+        );
+        assert_eq!(
+            _profile_configs(None, Some(CREDENTIALS), "minio").map(|configs| configs.len()),
+            Some(3)
+        );
+        assert_eq!(
+            _profile_configs(Some(CONFIG), Some(CREDENTIALS), "other"),
+            None
+        );
+        assert_eq!(_profile_configs(None, None, "default"), None);
+    }
+}
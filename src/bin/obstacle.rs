@@ -0,0 +1,130 @@
+//! Command line access to the urls and the local cache, as seen by the obstacle crate.
+//!
+//! Examples:
+//!     obstacle ls 's3://bucket/**/*.parquet' -o aws_region=us-east-1
+//!     obstacle fetch s3://bucket/data.parquet
+//!     obstacle cat file:///tmp/hello_world.txt
+//!     obstacle cache stats
+//!
+//! The cloud configuration is read from the environment, like `AWS_REGION` or `AWS_PROFILE`,
+//! and the `-o key=value` options override it.
+//!
+//! Run with:
+//!     cargo run --features cli,aws -- ls 's3://bucket/*.csv'
+
+use clap::{Parser, Subcommand};
+use obstacle::{cache, glob, open_url_with_options, CloudOptions, Mmap, ObstacleError};
+use std::io::{stdout, Write};
+use std::time::SystemTime;
+
+#[derive(Parser)]
+#[command(version, about = "Inspect cloud urls and the local cache.")]
+struct Cli {
+    /// Cloud configuration as key=value, for example aws_region=us-east-1.
+    /// Overrides the configuration read from the environment.
+    #[arg(short = 'o', long = "option", global = true)]
+    options: Vec<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the objects matching the url, `*` and `**` are expanded.
+    Ls { url: String },
+    /// Download the object to the local cache.
+    Fetch { url: String },
+    /// Print the content of the object.
+    Cat { url: String },
+    /// Manage the local cache.
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Show the space used by the cache and the cached objects.
+    Stats,
+    /// Evict the least recently used objects until the cache fits in the budget.
+    Prune {
+        /// The maximum number of bytes to keep.
+        #[arg(long)]
+        max_size: Option<u64>,
+        /// The maximum number of objects to keep.
+        #[arg(long)]
+        max_files: Option<usize>,
+    },
+    /// Remove all the objects from the cache.
+    Clear,
+}
+
+/// Build the cloud options for the url from the environment, with the key=value pairs on the command line on top.
+fn cloud_options(url: &str, options: &[String]) -> Result<CloudOptions, ObstacleError> {
+    let pairs = options
+        .iter()
+        .map(|option| {
+            option.split_once('=').ok_or_else(|| {
+                ObstacleError::new(format!("expected key=value option, got {}", option))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    CloudOptions::from_env().with_untyped_config(url, pairs)
+}
+
+fn stats() -> Result<(), ObstacleError> {
+    let now = SystemTime::now();
+    for entry in cache::list()? {
+        let age = now
+            .duration_since(entry.last_access)
+            .map(|age| age.as_secs())
+            .unwrap_or_default();
+        println!(
+            "{:>12} {:>8}s {}{} {}",
+            entry.size,
+            age,
+            entry.url,
+            if entry.is_sparse { " (sparse)" } else { "" },
+            entry.e_tag
+        );
+    }
+    let usage = cache::usage()?;
+    println!("total: {} bytes in {} objects", usage.size, usage.entries);
+    Ok(())
+}
+
+pub fn main() -> Result<(), ObstacleError> {
+    let cli = Cli::parse();
+    match cli.command {
+        Command::Ls { url } => {
+            let options = cloud_options(&url, &cli.options)?;
+            for found in glob(&url, Some(&options))? {
+                println!("{}", found);
+            }
+        }
+        Command::Fetch { url } => {
+            let options = cloud_options(&url, &cli.options)?;
+            match open_url_with_options(&url, &options)? {
+                Some(file) => println!("{} {} bytes", url, file.metadata()?.len()),
+                None => return Err(ObstacleError::new(format!("{} not found", url))),
+            }
+        }
+        Command::Cat { url } => {
+            let options = cloud_options(&url, &cli.options)?;
+            match Mmap::from_url_with_options(&url, &options)? {
+                Some(mmaped) => stdout().lock().write_all(&mmaped)?,
+                None => return Err(ObstacleError::new(format!("{} not found", url))),
+            }
+        }
+        Command::Cache { command } => match command {
+            CacheCommand::Stats => stats()?,
+            CacheCommand::Prune {
+                max_size,
+                max_files,
+            } => println!("evicted {} objects", cache::prune(max_size, max_files)?),
+            CacheCommand::Clear => println!("evicted {} objects", cache::clear()?),
+        },
+    }
+    Ok(())
+}
@@ -1,32 +1,77 @@
 //! Cache files locally in order to provide a mmap interface and provide faster access.
 //!
-//! When saving a file locally we create a directory structure that mirrors the cloud under ~/.cache/obstinate.
-//! Each url becomes a folder and the content of the file is saved with a name based on the e-tag of the file.
+//! When saving a file locally we create a directory structure that mirrors the cloud under the cache root,
+//! `~/.cache/obstinate` by default, see `CacheConfig`.
+//! Each url becomes a folder and the content of the file is saved with a name based on the cache key of the file,
+//! the e-tag by default, see `CacheKeyStrategy`.
+//! Partially downloaded files are saved as `sparse_<key>` until all their blocks have been fetched,
+//! the fetched blocks are recorded in `blocks_<key>`. The url, size, modification time and digest of the object,
+//! and the last validation of the content against the cloud, are recorded in `meta_<key>`, see `EntryMetadata`.
+//!
+//! The processes sharing the cache coordinate through an advisory lock on the `lock` file of each object.
+//!
+//! The content of the cache can be inspected with `list()` and `usage()` and managed with `evict()`,
+//! `evict_prefix()` and `clear()`.
 
+use crate::cache_config::{CacheConfig, CachePolicy};
+use crate::cache_key::{last_modified_key, version_range, CachedVersion};
+use crate::cache_path::{
+    cache_file_name, decode_component, decode_scoped_component, encode_component,
+    encode_scoped_component, parse_cache_file_name,
+};
+use crate::context::Obstacle;
 use crate::err::{obstinate_err, ObstacleError};
+use crate::eviction::{self, enforce_budget, touch};
 use crate::glob::CloudLocation;
-use crate::{build, get_cloud_options};
+use crate::lock::CacheLock;
+use crate::metadata::{self, guess_content_type};
+#[cfg(unix)]
+use crate::sparse::SparseFile;
+use crate::CloudOptions;
+use chrono::Utc;
 use futures_util::StreamExt;
-use home::home_dir;
-use log::debug;
+use log::{debug, warn};
 use object_store::path::Path as ObjectStorePath;
-use object_store::{GetOptions, ObjectStore};
+use object_store::{GetOptions, GetResult, ObjectMeta, ObjectStore};
+use std::collections::BTreeMap;
+#[cfg(unix)]
+use std::fs::OpenOptions;
 use std::fs::{create_dir_all, File};
-use std::io::Write;
-use std::path::{self, PathBuf};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{ErrorKind, Seek, Write};
+use std::path::{self, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use tokio::fs::{read_dir, remove_file, rename};
+use tokio::sync::OnceCell;
 use uuid::Uuid;
 
+pub use crate::metadata::EntryMetadata;
+
+/// The cache directory for a given url, without creating it.
+///
+/// The scope identifies the service holding the bucket, see `CloudOptions::service_scope()`.
+fn _cache_path_for_cloud_location(
+    config: &CacheConfig,
+    location: &CloudLocation,
+    scope: Option<&str>,
+) -> Result<PathBuf, ObstacleError> {
+    let mut base = config.root()?;
+    base.push(encode_component(&location.scheme));
+    base.push(encode_scoped_component(&location.bucket, scope));
+    base.push(encode_component(location.prefix.trim_start_matches('/')));
+    Ok(base)
+}
+
 /// Build a local file for caching a given url.
-/// We use the full url, including the file name, as the directory name.
+/// We use the full key, including the file name, as the directory name, see `encode_component()`.
 /// This allows multiple versions of the same file to be cached.
-fn _local_path_for_cloud_location(location: &CloudLocation) -> Result<PathBuf, ObstacleError> {
-    let mut base = home_dir().unwrap();
-    base.push(".cache/obstinate");
-    create_dir_all(&base)?;
-    base.push(&location.scheme);
-    base.push(&location.bucket);
-    base.push(location.prefix.trim_start_matches("/"));
+fn _local_path_for_cloud_location(
+    config: &CacheConfig,
+    location: &CloudLocation,
+    scope: Option<&str>,
+) -> Result<PathBuf, ObstacleError> {
+    let base = _cache_path_for_cloud_location(config, location, scope)?;
     if !base.try_exists()? {
         debug!("creating directory {}", base.display());
         create_dir_all(&base)?;
@@ -34,9 +79,32 @@ fn _local_path_for_cloud_location(location: &CloudLocation) -> Result<PathBuf, O
     Ok(base)
 }
 
-/// Delete any other content_* files that do not match the active content.
-async fn _cleanup_content(local_path: &PathBuf, active_content: &str) -> Result<(), ObstacleError> {
+/// Find the url of a file saved in the cache, this is the reverse of `_local_path_for_cloud_location`.
+/// Return None for shortened keys, their url is only available in the metadata.
+/// The scope of the bucket is not part of the url.
+fn _url_for_cached_file(root: &Path, path: &Path) -> Option<String> {
+    let components = path
+        .parent()?
+        .strip_prefix(root)
+        .ok()?
+        .iter()
+        .enumerate()
+        .map(|(index, component)| match index {
+            1 => decode_scoped_component(&component.to_string_lossy()),
+            _ => decode_component(&component.to_string_lossy()),
+        })
+        .collect::<Option<Vec<_>>>()?;
+    match components.as_slice() {
+        [scheme, _, key] if scheme == "file" => Some(format!("file:///{}", key)),
+        [scheme, bucket, key] => Some(format!("{}://{}/{}", scheme, bucket, key)),
+        _ => None,
+    }
+}
+
+/// Delete any other content_*, sparse_*, blocks_* and meta_* files that do not match the active key.
+async fn _cleanup_content(local_path: &PathBuf, active_key: &str) -> Result<(), ObstacleError> {
     debug!("cleaning up {}", local_path.display());
+    let active_key = encode_component(active_key);
     let mut dir = read_dir(&local_path).await?;
     loop {
         let entry = dir.next_entry().await?;
@@ -45,10 +113,16 @@ async fn _cleanup_content(local_path: &PathBuf, active_content: &str) -> Result<
             Some(entry) => {
                 let file_name = entry.file_name();
                 let file_name_str = file_name.to_string_lossy();
-                if file_name_str == active_content {
-                    continue;
-                }
-                if !file_name_str.starts_with("content_") {
+                let key = match file_name_str
+                    .strip_prefix("content_")
+                    .or_else(|| file_name_str.strip_prefix("sparse_"))
+                    .or_else(|| file_name_str.strip_prefix("blocks_"))
+                    .or_else(|| file_name_str.strip_prefix("meta_"))
+                {
+                    Some(key) => key,
+                    None => continue,
+                };
+                if key == active_key {
                     continue;
                 }
                 debug!("removing {}", file_name_str);
@@ -60,111 +134,638 @@ async fn _cleanup_content(local_path: &PathBuf, active_content: &str) -> Result<
 }
 
 enum DownloadResult {
-    /// The file was downloaded and saved locally.
-    Downloaded(File),
-    /// The file was already downloaded and is available locally.
-    Cached(File),
+    /// The file was downloaded and saved locally at the path.
+    Downloaded(PathBuf),
+    /// The file was already downloaded and is available locally at the path.
+    Cached(PathBuf),
     /// The file changed in the cloud during the download process.
     Retry,
     /// The file was not found.
     NotFound,
 }
 
+/// Find the cache key of the content cached in the directory, if any.
+async fn _cached_key(local_path: &PathBuf) -> Result<Option<String>, ObstacleError> {
+    let mut dir = match read_dir(&local_path).await {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = dir.next_entry().await? {
+        let file_name = entry.file_name();
+        if let Some(("content", key)) = parse_cache_file_name(&file_name.to_string_lossy()) {
+            return Ok(Some(key));
+        }
+    }
+    Ok(None)
+}
+
+/// The location of the cached content with the key.
+fn _content_path(local_base: &Path, key: &str) -> PathBuf {
+    local_base.join(cache_file_name("content", key))
+}
+
+/// Record the metadata of the content downloaded for the object.
+fn _record_download(
+    local_base: &Path,
+    key: &str,
+    url: &str,
+    meta: &ObjectMeta,
+    digest: Option<String>,
+) -> Result<(), ObstacleError> {
+    let now = Utc::now();
+    metadata::write(
+        local_base,
+        key,
+        &EntryMetadata {
+            url: url.to_string(),
+            size: meta.size as u64,
+            last_modified: meta.last_modified,
+            content_type: guess_content_type(url),
+            downloaded_at: now,
+            validated_at: now,
+            digest,
+        },
+    )
+}
+
+/// Record a successful validation of the cached content, keeping the digest computed at download.
+/// The metadata of the object is only available when the cloud returned it.
+fn _record_validation(
+    local_base: &Path,
+    key: &str,
+    url: &str,
+    meta: Option<&ObjectMeta>,
+) -> Result<(), ObstacleError> {
+    let mut entry = match (metadata::read(local_base, key), meta) {
+        (Some(entry), _) => entry,
+        (None, Some(meta)) => return _record_download(local_base, key, url, meta, None),
+        (None, None) => return Ok(()),
+    };
+    entry.validated_at = Utc::now();
+    if let Some(meta) = meta {
+        entry.size = meta.size as u64;
+        entry.last_modified = meta.last_modified;
+    }
+    metadata::write(local_base, key, &entry)
+}
+
+/// Open the cached content after checking its size and its digest, the size defaults to the recorded one.
+/// Return None when the content is missing or corrupted.
+///
+/// Corrupted content is only removed with the lock of the directory, another process may be replacing it.
+fn _open_verified(
+    local_base: &Path,
+    key: &str,
+    size: Option<u64>,
+    config: &CacheConfig,
+    lock: Option<&CacheLock>,
+) -> Result<Option<File>, ObstacleError> {
+    let local_path = local_base.join(cache_file_name("content", key));
+    let mut file = match File::open(&local_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let entry = metadata::read(local_base, key);
+    let expected_size = size.or_else(|| entry.as_ref().map(|entry| entry.size));
+    let actual_size = file.metadata()?.len();
+    let mut corrupted = expected_size.is_some_and(|expected_size| expected_size != actual_size);
+    let digest = entry.and_then(|entry| entry.digest);
+    if let (false, Some(checksum), Some(digest)) = (corrupted, config.checksum(), digest) {
+        if checksum.computed(&digest) {
+            corrupted = checksum.digest(&mut file)? != digest;
+            file.rewind()?;
+        }
+    }
+    if corrupted && lock.is_none() {
+        warn!("corrupted file {}", local_path.display());
+        return Ok(None);
+    }
+    if corrupted {
+        warn!("removing corrupted file {}", local_path.display());
+        for path in [local_path, metadata::metadata_path(local_base, key)] {
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        return Ok(None);
+    }
+    touch(&local_path);
+    Ok(Some(file))
+}
+
+/// Download the object with a single request, the cache key is derived from the metadata of the response.
+///
+/// When a version of the object is already cached the request is conditional, see `CacheKeyStrategy::revalidation()`,
+/// a not modified response is served from the cache.
 async fn _download_one(
+    url: &str,
     cloud_location: &CloudLocation,
-    object_store: &Box<dyn ObjectStore>,
+    object_store: &Arc<dyn ObjectStore>,
+    config: &CacheConfig,
+    scope: Option<&str>,
 ) -> Result<DownloadResult, ObstacleError> {
     let os_path: ObjectStorePath = ObjectStorePath::from_url_path(&cloud_location.prefix)?;
+    // The directory is only created once the object is found, missing objects leave no trace in the cache.
+    let local_base = _cache_path_for_cloud_location(config, cloud_location, scope)?;
 
-    // Get the active e-tag for the object in the cloud, this cannot be read as part of the `get*` call.
-    // https://github.com/apache/arrow-rs/discussions/4495
-
-    debug!("getting metadata for {}", os_path);
-    let cloud_metadata = object_store.head(&os_path).await?;
-    let desired_filename = format!(
-        "content_{}",
-        cloud_metadata.e_tag.clone().unwrap_or("default".into())
-    );
-    debug!("desired filename {}", desired_filename);
+    let strategy = config.key_strategy(&cloud_location.scheme);
+    let cached_key = _cached_key(&local_base).await?;
+    let entry = cached_key
+        .as_ref()
+        .and_then(|key| metadata::read(&local_base, key));
 
-    // check if we have a local copy of the file and return it if we do.
-    let local_base = _local_path_for_cloud_location(&cloud_location)?;
-    let local_path = local_base.join(path::Path::new(&desired_filename));
-    if local_path.exists() {
-        debug!("returning existing file {}", local_path.display());
-        return Ok(DownloadResult::Cached(File::open(local_path)?));
+    // Within the freshness window the cached content is served without contacting the cloud.
+    if let (Some(key), Some(ttl), Some(entry)) = (&cached_key, config.ttl(), &entry) {
+        let elapsed = (Utc::now() - entry.validated_at)
+            .to_std()
+            .unwrap_or_default();
+        if elapsed < ttl && _open_verified(&local_base, key, None, config, None)?.is_some() {
+            debug!("returning fresh file for {}", key);
+            return Ok(DownloadResult::Cached(_content_path(&local_base, key)));
+        }
     }
 
-    debug!("about to cleanup");
+    debug!("getting {}, cached key {:?}", os_path, cached_key);
+    let get_options = match &cached_key {
+        Some(key) => strategy.revalidation(&CachedVersion {
+            key: key.clone(),
+            last_modified: entry.map(|entry| entry.last_modified),
+        }),
+        None => GetOptions::default(),
+    };
+    let get_result = match object_store.get_opts(&os_path, get_options).await {
+        Ok(get_result) => get_result,
+        Err(object_store::Error::NotModified { .. }) => {
+            let key = cached_key.unwrap();
+            debug!("returning not modified file for {}", key);
+            return match _open_verified(&local_base, &key, None, config, None)? {
+                Some(_) => {
+                    _record_validation(&local_base, &key, url, None)?;
+                    Ok(DownloadResult::Cached(_content_path(&local_base, &key)))
+                }
+                // Evicted by another process since the key was read, or corrupted.
+                None => {
+                    // Remove corrupted content, the next attempt downloads the object again.
+                    let lock = CacheLock::exclusive(&local_base).await?;
+                    _open_verified(&local_base, &key, None, config, Some(&lock))?;
+                    Ok(DownloadResult::Retry)
+                }
+            };
+        }
+        Err(err) => return _get_error_result(err),
+    };
+    let meta = get_result.meta.clone();
+    let key = strategy.key(&meta);
+    debug!("cache key {:?}", key);
 
-    // Delete any old content_* files and download the latest version.
-    _cleanup_content(&local_base, &desired_filename).await?;
+    // Only one process downloads the object, the others wait for the lock and reuse the download.
+    _local_path_for_cloud_location(config, cloud_location, scope)?;
+    let lock = match CacheLock::try_exclusive(&local_base)? {
+        Some(lock) => lock,
+        None => {
+            // The response would sit unread for the whole download of the other process and time out.
+            drop(get_result);
+            debug!("waiting for the download of another process");
+            drop(CacheLock::exclusive(&local_base).await?);
+            return Ok(DownloadResult::Retry);
+        }
+    };
+    if let Some(key) = &key {
+        if _open_verified(
+            &local_base,
+            key,
+            Some(meta.size as u64),
+            config,
+            Some(&lock),
+        )?
+        .is_some()
+        {
+            debug!("returning existing file for {}", key);
+            _record_validation(&local_base, key, url, Some(&meta))?;
+            return Ok(DownloadResult::Cached(_content_path(&local_base, key)));
+        }
+
+        // Delete any old content_* files and download the latest version.
+        _cleanup_content(&local_base, key).await?;
+    }
 
     debug!("About to download");
-    // Download the file if it matches the e-tag.
-    let get_options = GetOptions {
-        if_match: cloud_metadata.e_tag,
-        ..GetOptions::default()
+    // Create a temporary file and save the object to it.
+    let tempfile = local_base.join(path::Path::new(&format!("temp_{}", Uuid::new_v4())));
+    let outcome = if config.download_concurrency() > 1
+        && get_result.meta.size > config.download_part_size()
+    {
+        _download_parts(object_store, &os_path, get_result, &tempfile, config).await
+    } else {
+        _download_stream(get_result, &tempfile).await
     };
-    let get_result = object_store.get_opts(&os_path, get_options).await;
-
-    match get_result {
-        Err(ref err) => match err {
-            // The object has changed in the cloud, loop.
-            object_store::Error::Precondition { .. } => {
-                debug!("object changed in the cloud, retrying");
-                return Ok(DownloadResult::Retry);
+    match outcome {
+        Ok(None) => {}
+        Ok(Some(result)) => {
+            let _ = remove_file(&tempfile).await;
+            return Ok(result);
+        }
+        Err(err) => {
+            let _ = remove_file(&tempfile).await;
+            return Err(err);
+        }
+    }
+
+    // A truncated or corrupted download is discarded and the object downloaded again.
+    let digest = match _digest_download(&tempfile, &meta, config) {
+        Ok(Some(digest)) => digest,
+        Ok(None) => {
+            let _ = remove_file(&tempfile).await;
+            return Ok(DownloadResult::Retry);
+        }
+        Err(err) => {
+            let _ = remove_file(&tempfile).await;
+            return Err(err);
+        }
+    };
+
+    // Keys derived from the content are only known once the download completes.
+    let key = match key {
+        Some(key) => key,
+        None => {
+            let key = match strategy.content_key(&mut File::open(&tempfile)?) {
+                Ok(key) => key,
+                Err(err) => {
+                    let _ = remove_file(&tempfile).await;
+                    return Err(err);
+                }
+            };
+            if _open_verified(
+                &local_base,
+                &key,
+                Some(meta.size as u64),
+                config,
+                Some(&lock),
+            )?
+            .is_some()
+            {
+                let _ = remove_file(&tempfile).await;
+                debug!("returning unchanged file for {}", key);
+                _record_validation(&local_base, &key, url, Some(&meta))?;
+                return Ok(DownloadResult::Cached(_content_path(&local_base, &key)));
             }
-            object_store::Error::NotFound { .. } => {
-                // The object does not exist in the cloud, return None.
-                debug!("object not found in the cloud");
-                return Ok(DownloadResult::NotFound);
+            _cleanup_content(&local_base, &key).await?;
+            key
+        }
+    };
+
+    // Now rename the successful download to the desired filename.
+    let local_path = _content_path(&local_base, &key);
+    rename(&tempfile, &local_path).await?;
+    // A sparse file of the same version is superseded by the full content.
+    for kind in ["sparse", "blocks"] {
+        match remove_file(local_base.join(cache_file_name(kind, &key))).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    _record_download(&local_base, &key, url, &meta, digest)?;
+    enforce_budget(config, &local_path, meta.size as u64, 1)?;
+    Ok(DownloadResult::Downloaded(local_path))
+}
+
+/// Check the downloaded file against the metadata of the object and the checksum supplied by the provider.
+/// Return None when the download is corrupted, otherwise the digest of the file when a checksum is configured.
+fn _digest_download(
+    tempfile: &Path,
+    meta: &ObjectMeta,
+    config: &CacheConfig,
+) -> Result<Option<Option<String>>, ObstacleError> {
+    let mut file = File::open(tempfile)?;
+    let size = file.metadata()?.len();
+    if size != meta.size as u64 {
+        warn!(
+            "downloaded {} bytes of {}, expected {}",
+            size, meta.location, meta.size
+        );
+        return Ok(None);
+    }
+    let checksum = match config.checksum() {
+        Some(checksum) => checksum,
+        None => return Ok(Some(None)),
+    };
+    let digest = checksum.digest(&mut file)?;
+    if let Some(expected) = checksum.provider_digest(meta) {
+        if expected != digest {
+            warn!(
+                "{} has digest {}, expected {}",
+                meta.location, digest, expected
+            );
+            return Ok(None);
+        }
+    }
+    Ok(Some(Some(digest)))
+}
+
+/// Map the errors of a conditional get to the result of the download.
+fn _get_error_result(err: object_store::Error) -> Result<DownloadResult, ObstacleError> {
+    match err {
+        // The object has changed in the cloud, loop.
+        object_store::Error::Precondition { .. } => {
+            debug!("object changed in the cloud, retrying");
+            Ok(DownloadResult::Retry)
+        }
+        object_store::Error::NotFound { .. } => {
+            // The object does not exist in the cloud, return None.
+            debug!("object not found in the cloud");
+            Ok(DownloadResult::NotFound)
+        }
+        _ => Err(ObstacleError::from_err(err)),
+    }
+}
+
+/// Stream the response of the get request to the temporary file.
+/// Return a result when the download cannot complete because of the object state in the cloud.
+async fn _download_stream(
+    get_result: GetResult,
+    tempfile: &Path,
+) -> Result<Option<DownloadResult>, ObstacleError> {
+    let mut stream = get_result.into_stream();
+    debug!("Downloading to temporary file {}.", tempfile.display());
+    let mut local_file = File::create(tempfile)?;
+    while let Some(buffer) = stream.next().await {
+        local_file.write_all(&buffer?)?;
+    }
+    local_file.sync_all()?;
+    Ok(None)
+}
+
+#[cfg(unix)]
+fn _write_all_at(file: &File, buffer: &[u8], offset: u64) -> Result<(), ObstacleError> {
+    use std::os::unix::fs::FileExt;
+    Ok(file.write_all_at(buffer, offset)?)
+}
+
+#[cfg(windows)]
+fn _write_all_at(file: &File, mut buffer: &[u8], mut offset: u64) -> Result<(), ObstacleError> {
+    use std::os::windows::fs::FileExt;
+    while !buffer.is_empty() {
+        let written = file.seek_write(buffer, offset)?;
+        buffer = &buffer[written..];
+        offset += written as u64;
+    }
+    Ok(())
+}
+
+/// Download the object with concurrent ranged requests, each part is written at its offset in the temporary file.
+///
+/// The first part is read from the response of the initial get request, which is then dropped.
+/// All the other parts are requested with the same e-tag, a change in the cloud during the download fails the download.
+async fn _download_parts(
+    object_store: &Arc<dyn ObjectStore>,
+    os_path: &ObjectStorePath,
+    get_result: GetResult,
+    tempfile: &Path,
+    config: &CacheConfig,
+) -> Result<Option<DownloadResult>, ObstacleError> {
+    let part_size = config.download_part_size();
+    let size = get_result.meta.size;
+    let meta = get_result.meta.clone();
+    debug!(
+        "Downloading {} parts to temporary file {}.",
+        size.div_ceil(part_size),
+        tempfile.display()
+    );
+    let local_file = File::create(tempfile)?;
+    local_file.set_len(size as u64)?;
+
+    let mut written = 0;
+    let mut stream = get_result.into_stream();
+    while written < part_size {
+        match stream.next().await {
+            Some(buffer) => {
+                let buffer = buffer?;
+                _write_all_at(&local_file, &buffer, written as u64)?;
+                written += buffer.len();
             }
-            _ => return Err(ObstacleError::new(err.to_string())),
-        },
-        Ok(result) => {
-            let mut stream = result.into_stream();
-
-            // Create a temporary file and download the object to it.
-            let tempfile = local_base.join(path::Path::new(&format!(
-                "temp_{}",
-                Uuid::new_v4().to_string()
-            )));
-            {
-                debug!("Downloading to temporary file {}.", tempfile.display());
-                let mut local_file = File::create(&tempfile)?;
-                while let Some(buffer) = stream.next().await {
-                    let bytes = buffer.unwrap();
-                    local_file.write_all(&bytes)?;
+            None => break,
+        }
+    }
+    drop(stream);
+
+    let parts = (written..size)
+        .step_by(part_size)
+        .map(|start| start..(start + part_size).min(size));
+    let results = futures::stream::iter(parts)
+        .map(|part| {
+            // The parts must come from the version of the first response.
+            let get_options = version_range(&meta, part.clone());
+            let local_file = &local_file;
+            async move {
+                let bytes = match object_store.get_opts(os_path, get_options).await {
+                    Ok(result) => result.bytes().await?,
+                    Err(err) => return _get_error_result(err).map(Some),
+                };
+                if bytes.len() != part.len() {
+                    return obstinate_err(format!(
+                        "expected {} bytes for range {:?} of {}, got {}",
+                        part.len(),
+                        part,
+                        os_path,
+                        bytes.len()
+                    ));
                 }
-                local_file.sync_all()?;
+                _write_all_at(local_file, &bytes, part.start as u64)?;
+                Ok(None)
             }
-            // Now rename the successful download to the desired filename.
-            rename(&tempfile, &local_path).await?;
-
-            // Return the cached file.
-            let file = File::open(local_path).unwrap();
-            return Ok(DownloadResult::Downloaded(file));
+        })
+        .buffer_unordered(config.download_concurrency());
+    futures::pin_mut!(results);
+    while let Some(result) = results.next().await {
+        if let Some(result) = result? {
+            return Ok(Some(result));
         }
     }
+    local_file.sync_all()?;
+    Ok(None)
 }
 
+/// The downloads in progress in this process, keyed by cache root, url and hash of the cloud options.
+static IN_FLIGHT: Mutex<BTreeMap<InFlightKey, InFlight>> = Mutex::new(BTreeMap::new());
+
+type InFlightKey = (PathBuf, String, u64);
+
+/// The path of the cached content, each caller opens its own file so that they do not share the file offset.
+type InFlight = Arc<OnceCell<Result<Option<PathBuf>, ObstacleError>>>;
+
 /// Download a file from the cloud and cache it locally.
 ///
-/// Because of current limits in the API we need to use head() to get the e-tag and then download with get_opts().
-/// This is not ideal because:
-/// 1. we need to make two calls to the cloud to download a file.
-/// 2. there is a race condition where an object could change between the head() and get_opts() calls.
+/// Concurrent calls for the same url in this process share a single download and receive the same file.
+///
+/// The download uses a single get request and the cache key derived from its metadata. When the object is
+/// already cached the request is conditional, an unchanged object is served from the cache without
+/// transferring its content.
 ///
 pub async fn download_file(url: &str) -> Result<Option<File>, ObstacleError> {
-    let cloud_options = get_cloud_options();
+    Obstacle::global().download_file(url).await
+}
+
+/// Download a file from the cloud and cache it locally, using the given options instead of the configured ones.
+pub async fn download_file_with_options(
+    url: &str,
+    cloud_options: &CloudOptions,
+) -> Result<Option<File>, ObstacleError> {
+    let obstacle = Obstacle::global();
+    download(
+        obstacle,
+        url,
+        obstacle.cache_config().policy(),
+        Some(cloud_options),
+    )
+    .await
+}
+
+/// Download a file from the cloud and cache it locally, using the given policy instead of the configured one.
+pub async fn download_file_with_policy(
+    url: &str,
+    policy: CachePolicy,
+) -> Result<Option<File>, ObstacleError> {
+    download(Obstacle::global(), url, policy, None).await
+}
 
-    let (cloud_location, object_store) = build(url, cloud_options)?;
+/// Download a file with the configuration and the stores of the instance.
+pub(crate) async fn download(
+    obstacle: &Obstacle,
+    url: &str,
+    policy: CachePolicy,
+    cloud_options: Option<&CloudOptions>,
+) -> Result<Option<File>, ObstacleError> {
+    let config = obstacle.cache_config();
+    let cloud_options = cloud_options
+        .cloned()
+        .or_else(|| obstacle.resolve_cloud_options(url));
+    if policy == CachePolicy::Offline {
+        return Ok(Some(_open_offline(&config, url, cloud_options.as_ref())?));
+    }
+    // Calls with different options may reach different services or be denied access, they do not share downloads.
+    let mut hasher = DefaultHasher::new();
+    cloud_options.hash(&mut hasher);
+    let in_flight_key = (config.root()?, url.to_string(), hasher.finish());
+    for _attempt in 0..10 {
+        let path = match _download_shared(
+            obstacle,
+            &config,
+            url,
+            cloud_options.as_ref(),
+            &in_flight_key,
+        )
+        .await?
+        {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        match File::open(&path) {
+            Ok(file) => return Ok(Some(file)),
+            // Evicted since the download completed, download again.
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    obstinate_err(format!("the cached content of {} keeps being evicted", url))
+}
+
+/// Download the file, concurrent calls with the same key share a single download.
+async fn _download_shared(
+    obstacle: &Obstacle,
+    config: &CacheConfig,
+    url: &str,
+    cloud_options: Option<&CloudOptions>,
+    in_flight_key: &InFlightKey,
+) -> Result<Option<PathBuf>, ObstacleError> {
+    let in_flight = IN_FLIGHT
+        .lock()
+        .unwrap()
+        .entry(in_flight_key.clone())
+        .or_default()
+        .clone();
+    let result = in_flight
+        .get_or_init(|| _download_file(obstacle, config, url, cloud_options))
+        .await;
+    {
+        // The first caller to complete removes the entry, later calls check the cache again.
+        let mut all_in_flight = IN_FLIGHT.lock().unwrap();
+        if all_in_flight
+            .get(in_flight_key)
+            .is_some_and(|existing| Arc::ptr_eq(existing, &in_flight))
+        {
+            all_in_flight.remove(in_flight_key);
+        }
+    }
+    result.clone()
+}
+
+/// Open the most recent content cached for the url, without any network access.
+fn _open_offline(
+    config: &CacheConfig,
+    url: &str,
+    cloud_options: Option<&CloudOptions>,
+) -> Result<File, ObstacleError> {
+    let scope = cloud_options.and_then(|options| options.service_scope(url));
+    let local_base =
+        _cache_path_for_cloud_location(config, &CloudLocation::new(url)?, scope.as_deref())?;
+    let mut latest: Option<(SystemTime, String)> = None;
+    let entries = match std::fs::read_dir(&local_base) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return obstinate_err(format!("{} is not cached and the cache is offline", url))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let key = match parse_cache_file_name(&file_name.to_string_lossy()) {
+            Some(("content", key)) => key,
+            _ => continue,
+        };
+        let modified = entry.metadata()?.modified()?;
+        if latest.as_ref().is_none_or(|(latest, _)| modified > *latest) {
+            latest = Some((modified, key));
+        }
+    }
+    let key = match latest {
+        Some((_, key)) => key,
+        None => return obstinate_err(format!("{} is not cached and the cache is offline", url)),
+    };
+    debug!("returning offline file for {}", key);
+    match _open_verified(&local_base, &key, None, config, None)? {
+        Some(file) => Ok(file),
+        None => obstinate_err(format!(
+            "the cached content of {} is corrupted and the cache is offline",
+            url
+        )),
+    }
+}
+
+async fn _download_file(
+    obstacle: &Obstacle,
+    config: &CacheConfig,
+    url: &str,
+    cloud_options: Option<&CloudOptions>,
+) -> Result<Option<PathBuf>, ObstacleError> {
+    let (cloud_location, object_store) = obstacle.store(url, cloud_options)?;
+    let scope = cloud_options.and_then(|options| options.service_scope(url));
     for _attempt in 0..10 {
         debug!("attempt {} at downloading {}", _attempt, url);
-        match _download_one(&cloud_location, &object_store).await {
-            Ok(DownloadResult::Downloaded(file)) => return Ok(Some(file)),
-            Ok(DownloadResult::Cached(file)) => return Ok(Some(file)),
+        match _download_one(
+            url,
+            &cloud_location,
+            &object_store,
+            config,
+            scope.as_deref(),
+        )
+        .await
+        {
+            Ok(DownloadResult::Downloaded(path)) => return Ok(Some(path)),
+            Ok(DownloadResult::Cached(path)) => return Ok(Some(path)),
             Ok(DownloadResult::Retry) => continue,
             Ok(DownloadResult::NotFound) => return Ok(None),
             Err(err) => return Err(err),
@@ -172,3 +773,552 @@ pub async fn download_file(url: &str) -> Result<Option<File>, ObstacleError> {
     }
     return obstinate_err("Failed to download file after 10 attempts");
 }
+
+/// The result of opening a url for sparse access.
+#[cfg(unix)]
+pub(crate) enum SparseOpenResult {
+    /// The full content is already available locally.
+    Cached(File),
+    /// Only the ranges requested on the sparse file will be downloaded.
+    Sparse(Box<SparseFile>),
+}
+
+/// Prepare a sparse local file for the given url, no content is downloaded at this point.
+#[cfg(unix)]
+pub(crate) async fn open_sparse(
+    obstacle: &Obstacle,
+    url: &str,
+    cloud_options: Option<&CloudOptions>,
+) -> Result<Option<SparseOpenResult>, ObstacleError> {
+    let config = obstacle.cache_config();
+    let cloud_options = cloud_options
+        .cloned()
+        .or_else(|| obstacle.resolve_cloud_options(url));
+    if config.policy() == CachePolicy::Offline {
+        return Ok(Some(SparseOpenResult::Cached(_open_offline(
+            &config,
+            url,
+            cloud_options.as_ref(),
+        )?)));
+    }
+    let (cloud_location, object_store) = obstacle.store(url, cloud_options.as_ref())?;
+    let scope = cloud_options
+        .as_ref()
+        .and_then(|options| options.service_scope(url));
+    let os_path: ObjectStorePath = ObjectStorePath::from_url_path(&cloud_location.prefix)?;
+
+    debug!("getting metadata for {}", os_path);
+    let cloud_metadata = match object_store.head(&os_path).await {
+        Ok(cloud_metadata) => cloud_metadata,
+        Err(object_store::Error::NotFound { .. }) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    // Keys derived from the content are not known before the download, the sparse file uses the metadata instead.
+    let key = config
+        .key_strategy(&cloud_location.scheme)
+        .key(&cloud_metadata)
+        .unwrap_or_else(|| last_modified_key(&cloud_metadata));
+
+    let local_base = _local_path_for_cloud_location(&config, &cloud_location, scope.as_deref())?;
+    let size = Some(cloud_metadata.size as u64);
+    if let Some(file) = _open_verified(&local_base, &key, size, &config, None)? {
+        debug!("returning existing file for {}", key);
+        return Ok(Some(SparseOpenResult::Cached(file)));
+    }
+    let lock = CacheLock::exclusive(&local_base).await?;
+    if let Some(file) = _open_verified(&local_base, &key, size, &config, Some(&lock))? {
+        debug!("returning file downloaded by another process for {}", key);
+        return Ok(Some(SparseOpenResult::Cached(file)));
+    }
+    _cleanup_content(&local_base, &key).await?;
+
+    let sparse_path = local_base.join(cache_file_name("sparse", &key));
+    debug!("opening sparse file {}", sparse_path.display());
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&sparse_path)?;
+    file.set_len(cloud_metadata.size as u64)?;
+    touch(&sparse_path);
+    if metadata::read(&local_base, &key).is_none() {
+        _record_download(&local_base, &key, url, &cloud_metadata, None)?;
+    }
+    Ok(Some(SparseOpenResult::Sparse(Box::new(SparseFile::new(
+        object_store,
+        cloud_metadata,
+        file,
+        &local_base,
+        &key,
+        config,
+    )?))))
+}
+
+/// An object saved in the cache.
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    /// The url of the object in the cloud.
+    pub url: String,
+    /// The cache key of the cached version of the object, the e-tag by default.
+    pub e_tag: String,
+    /// The space used on disk.
+    pub size: u64,
+    /// The last time the object was opened from the cache.
+    pub last_access: SystemTime,
+    /// True when only some blocks of the object have been downloaded.
+    pub is_sparse: bool,
+    /// The metadata recorded with the content, missing for content cached by older versions.
+    pub metadata: Option<EntryMetadata>,
+    path: PathBuf,
+}
+
+/// The space used by the cache.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheUsage {
+    /// The space used on disk.
+    pub size: u64,
+    /// The number of cached objects.
+    pub entries: usize,
+}
+
+/// List the objects saved in the cache, the least recently used first.
+pub fn list() -> Result<Vec<CacheEntry>, ObstacleError> {
+    Obstacle::global().list()
+}
+
+/// List the objects saved in the cache with the configuration.
+pub(crate) fn list_entries(config: &CacheConfig) -> Result<Vec<CacheEntry>, ObstacleError> {
+    let root = config.root()?;
+    let mut entries = eviction::scan(&root)?
+        .into_iter()
+        .filter_map(|file| {
+            let file_name = file.path.file_name()?.to_string_lossy();
+            let (kind, e_tag) = parse_cache_file_name(&file_name)?;
+            let is_sparse = kind == "sparse";
+            let metadata = metadata::read(file.path.parent()?, &e_tag);
+            let url = match &metadata {
+                Some(metadata) => metadata.url.clone(),
+                None => _url_for_cached_file(&root, &file.path)?,
+            };
+            Some(CacheEntry {
+                url,
+                e_tag,
+                size: file.size,
+                last_access: file.accessed,
+                is_sparse,
+                metadata,
+                path: file.path,
+            })
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.last_access);
+    Ok(entries)
+}
+
+/// Report the space used by the cache.
+pub fn usage() -> Result<CacheUsage, ObstacleError> {
+    Obstacle::global().usage()
+}
+
+/// Report the space used by the cache with the configuration.
+pub(crate) fn usage_of(config: &CacheConfig) -> Result<CacheUsage, ObstacleError> {
+    Ok(list_entries(config)?
+        .iter()
+        .fold(CacheUsage::default(), |usage, entry| CacheUsage {
+            size: usage.size + entry.size,
+            entries: usage.entries + 1,
+        }))
+}
+
+/// Remove the cached entries matching the predicate, return the number of entries removed.
+pub(crate) fn evict_matching<F: Fn(&CacheEntry) -> bool>(
+    config: &CacheConfig,
+    predicate: F,
+) -> Result<usize, ObstacleError> {
+    let mut evicted = 0;
+    for entry in list_entries(config)?
+        .iter()
+        .filter(|entry| predicate(entry))
+    {
+        if eviction::remove(&entry.path)? {
+            evicted += 1;
+        }
+    }
+    Ok(evicted)
+}
+
+/// Remove all the cached versions of the url, return the number of entries removed.
+pub fn evict(url: &str) -> Result<usize, ObstacleError> {
+    Obstacle::global().evict(url)
+}
+
+/// Remove the cached entries for all the urls starting with the prefix, return the number of entries removed.
+pub fn evict_prefix(prefix: &str) -> Result<usize, ObstacleError> {
+    Obstacle::global().evict_prefix(prefix)
+}
+
+/// Evict the least recently used entries until the cache fits in the budget, return the number of entries removed.
+pub fn prune(max_size: Option<u64>, max_files: Option<usize>) -> Result<usize, ObstacleError> {
+    Obstacle::global().prune(max_size, max_files)
+}
+
+/// Evict the least recently used entries of the cache with the configuration until it fits in the budget.
+pub(crate) fn prune_to(
+    config: &CacheConfig,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+) -> Result<usize, ObstacleError> {
+    eviction::evict_to_fit(&config.root()?, max_size, max_files, Path::new(""))
+}
+
+/// Remove all the entries from the cache, return the number of entries removed.
+pub fn clear() -> Result<usize, ObstacleError> {
+    Obstacle::global().clear()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::integrity::ChecksumAlgorithm;
+    use crate::runtime::block_on;
+    use std::io::Read;
+
+    #[cfg(feature = "aws")]
+    #[test]
+    fn test_cache_path_scope() {
+        use crate::AmazonS3ConfigKey;
+
+        let config = CacheConfig::default().with_root("/cache");
+        let url = "s3://bucket/a.csv";
+        let location = CloudLocation::new(url).unwrap();
+        let aws = CloudOptions::default().with_aws([(AmazonS3ConfigKey::Region, "us-east-1")]);
+        let minio = aws
+            .clone()
+            .with_aws([(AmazonS3ConfigKey::Endpoint, "http://localhost:9000")]);
+        let path = |options: &CloudOptions| {
+            let scope = options.service_scope(url);
+            _cache_path_for_cloud_location(&config, &location, scope.as_deref()).unwrap()
+        };
+        assert_eq!(path(&aws), Path::new("/cache/s3/bucket/a.csv"));
+        assert_ne!(path(&minio), path(&aws));
+        assert_eq!(
+            _url_for_cached_file(Path::new("/cache"), &path(&minio).join("content_1")).unwrap(),
+            url
+        );
+    }
+
+    #[test]
+    fn test_url_for_cached_file() {
+        let root = Path::new("/cache");
+        assert_eq!(
+            _url_for_cached_file(
+                root,
+                Path::new("/cache/s3/bucket/a%2Fb.parquet/content_1234")
+            ),
+            Some("s3://bucket/a/b.parquet".into())
+        );
+        assert_eq!(
+            _url_for_cached_file(root, Path::new("/cache/file/%/tmp%2Fa.csv/sparse_abc")),
+            Some("file:///tmp/a.csv".into())
+        );
+        assert_eq!(
+            _url_for_cached_file(
+                root,
+                Path::new("/cache/s3/bucket/a%2F..%2F..%2Fb/content_1234")
+            ),
+            Some("s3://bucket/a/../../b".into())
+        );
+        // The scope of the bucket is dropped.
+        assert_eq!(
+            _url_for_cached_file(
+                root,
+                Path::new("/cache/s3/bucket~0123456789abcdef/a.csv/content_1234")
+            ),
+            Some("s3://bucket/a.csv".into())
+        );
+        // Shortened keys.
+        assert_eq!(
+            _url_for_cached_file(root, Path::new("/cache/s3/bucket/a%2F~1234/content_1234")),
+            None
+        );
+        assert_eq!(
+            _url_for_cached_file(root, Path::new("/cache/s3/bucket/a/b/content_1234")),
+            None
+        );
+        assert_eq!(
+            _url_for_cached_file(root, Path::new("/other/s3/bucket/a/content_1234")),
+            None
+        );
+    }
+
+    /// An instance caching under its own directory, removed at the end of the test.
+    struct TestCache {
+        obstacle: Obstacle,
+        root: PathBuf,
+    }
+
+    impl TestCache {
+        fn new(name: &str, config: CacheConfig) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("obstacle_{}_{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            let obstacle = Obstacle::new().with_cache_config(config.with_root(&root));
+            TestCache { obstacle, root }
+        }
+    }
+
+    impl Drop for TestCache {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn test_memory_download() {
+        let cache = TestCache::new("download", CacheConfig::default());
+        let url = "memory://download/data/a.csv";
+        crate::put_memory_object(url, "a,b\n1,2\n").unwrap();
+
+        let mmap = cache.obstacle.mmap(url).unwrap().unwrap();
+        assert_eq!(&mmap[..], b"a,b\n1,2\n");
+        let entries = cache.obstacle.list().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].url, url);
+        assert_eq!(entries[0].metadata.as_ref().unwrap().size, 8);
+
+        // A new version replaces the cached one.
+        crate::put_memory_object(url, "a,b\n3,4\n5,6\n").unwrap();
+        let mmap = cache.obstacle.mmap(url).unwrap().unwrap();
+        assert_eq!(&mmap[..], b"a,b\n3,4\n5,6\n");
+        assert_eq!(cache.obstacle.usage().unwrap().entries, 1);
+
+        assert!(cache
+            .obstacle
+            .mmap("memory://download/missing.csv")
+            .unwrap()
+            .is_none());
+        assert!(!cache.root.join("memory/download/missing.csv").exists());
+        assert_eq!(cache.obstacle.evict(url).unwrap(), 1);
+        assert_eq!(cache.obstacle.usage().unwrap().entries, 0);
+    }
+
+    #[test]
+    fn test_memory_offline() {
+        let cache = TestCache::new("offline", CacheConfig::default());
+        let url = "memory://offline/a.csv";
+        crate::put_memory_object(url, "cached").unwrap();
+        let file = block_on(cache.obstacle.download_file(url)).unwrap();
+        assert!(file.is_some());
+
+        crate::delete_memory_object(url).unwrap();
+        let mut offline = block_on(download(&cache.obstacle, url, CachePolicy::Offline, None))
+            .unwrap()
+            .unwrap();
+        let mut content = String::new();
+        offline.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "cached");
+        // Online, the deleted object is not found.
+        assert!(cache.obstacle.open_url(url).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_memory_corrupted() {
+        let cache = TestCache::new("corrupted", CacheConfig::default());
+        let url = "memory://corrupted/a.csv";
+        crate::put_memory_object(url, "cached").unwrap();
+        block_on(cache.obstacle.download_file(url))
+            .unwrap()
+            .unwrap();
+        let local_base = cache.root.join("memory/corrupted/a.csv");
+        let key = block_on(_cached_key(&local_base)).unwrap().unwrap();
+        let path = _content_path(&local_base, &key);
+        std::fs::write(&path, "trunc").unwrap();
+
+        // Without the lock the corrupted content is reported but kept.
+        assert!(block_on(download(&cache.obstacle, url, CachePolicy::Offline, None)).is_err());
+        assert!(path.exists());
+        // The download replaces it.
+        let mut file = cache.obstacle.open_url(url).unwrap().unwrap();
+        let mut content = String::new();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "cached");
+    }
+
+    #[test]
+    fn test_memory_locked() {
+        let cache = TestCache::new("locked", CacheConfig::default());
+        let url = "memory://locked/a.csv";
+        crate::put_memory_object(url, "first").unwrap();
+        block_on(cache.obstacle.download_file(url))
+            .unwrap()
+            .unwrap();
+        crate::put_memory_object(url, "second").unwrap();
+
+        // Another process holds the lock, the download waits for it and requests the object again.
+        let lock = CacheLock::try_exclusive(&cache.root.join("memory/locked/a.csv"))
+            .unwrap()
+            .unwrap();
+        let obstacle = cache.obstacle.clone();
+        let download = std::thread::spawn(move || obstacle.open_url(url));
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!download.is_finished());
+        drop(lock);
+        let mut content = String::new();
+        download
+            .join()
+            .unwrap()
+            .unwrap()
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "second");
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let cache = TestCache::new("budget", CacheConfig::default().with_max_files(2));
+        for name in ["a", "b", "c"] {
+            let url = format!("memory://budget/{}.csv", name);
+            crate::put_memory_object(&url, name).unwrap();
+            block_on(cache.obstacle.download_file(&url))
+                .unwrap()
+                .unwrap();
+        }
+        let entries = cache.obstacle.list().unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry.url.as_str())
+                .collect::<Vec<_>>(),
+            vec!["memory://budget/b.csv", "memory://budget/c.csv"]
+        );
+    }
+
+    #[test]
+    fn test_memory_multipart() {
+        let config = CacheConfig::default()
+            .with_download_part_size(10)
+            .with_download_concurrency(3)
+            .with_checksum(ChecksumAlgorithm::Crc32c);
+        let cache = TestCache::new("multipart", config);
+        let url = "memory://multipart/numbers.txt";
+        let content = (0..100)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        crate::put_memory_object(url, content.clone()).unwrap();
+        let mmap = cache.obstacle.mmap(url).unwrap().unwrap();
+        assert_eq!(&mmap[..], content.as_bytes());
+        let entries = cache.obstacle.list().unwrap();
+        assert!(entries[0].metadata.as_ref().unwrap().digest.is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_memory_sparse() {
+        let cache = TestCache::new("sparse", CacheConfig::default());
+        let url = "memory://sparse/a.bin";
+        let content = vec![7u8; 3 * crate::sparse::BLOCK_SIZE / 2];
+        crate::put_memory_object(url, content.clone()).unwrap();
+        let mmap = cache.obstacle.mmap_sparse(url).unwrap().unwrap();
+        assert!(cache.obstacle.list().unwrap()[0].is_sparse);
+        mmap.advise(0..10, crate::Advice::Normal).unwrap();
+        assert_eq!(&mmap[..10], &content[..10]);
+        mmap.advise(0..mmap.len(), crate::Advice::Normal).unwrap();
+        assert_eq!(&mmap[..], &content[..]);
+        assert!(!cache.obstacle.list().unwrap()[0].is_sparse);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_memory_sparse_then_download() {
+        let cache = TestCache::new("sparse_download", CacheConfig::default());
+        let url = "memory://sparse_download/a.bin";
+        let content = vec![7u8; 3 * crate::sparse::BLOCK_SIZE / 2];
+        crate::put_memory_object(url, content.clone()).unwrap();
+        let sparse = cache.obstacle.mmap_sparse(url).unwrap().unwrap();
+        sparse.advise(0..10, crate::Advice::Normal).unwrap();
+        drop(sparse);
+
+        // The full download replaces the sparse file of the same version.
+        let mmap = cache.obstacle.mmap(url).unwrap().unwrap();
+        assert_eq!(&mmap[..], &content[..]);
+        let entries = cache.obstacle.list().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(!entries[0].is_sparse);
+        let local_base = entries[0].path.parent().unwrap();
+        let names = std::fs::read_dir(local_base)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert!(names
+            .iter()
+            .all(|name| !name.starts_with("sparse_") && !name.starts_with("blocks_")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_memory_sparse_changed() {
+        let cache = TestCache::new("sparse_changed", CacheConfig::default());
+        let url = "memory://sparse_changed/a.bin";
+        let size = 3 * crate::sparse::BLOCK_SIZE / 2;
+        crate::put_memory_object(url, vec![1u8; size]).unwrap();
+        let mmap = cache.obstacle.mmap_sparse(url).unwrap().unwrap();
+        mmap.advise(0..10, crate::Advice::Normal).unwrap();
+
+        // The blocks of the new version are not mixed with the ones already fetched.
+        crate::put_memory_object(url, vec![2u8; size]).unwrap();
+        assert!(mmap.advise(0..size, crate::Advice::Normal).is_err());
+        assert!(cache.obstacle.list().unwrap().is_empty());
+        let mmap = cache.obstacle.mmap_sparse(url).unwrap().unwrap();
+        mmap.advise(0..size, crate::Advice::Normal).unwrap();
+        assert!(mmap.iter().all(|byte| *byte == 2));
+    }
+
+    #[test]
+    fn test_memory_glob() {
+        let cache = TestCache::new("glob", CacheConfig::default());
+        for key in [
+            "data/a.csv",
+            "data/b.csv",
+            "data/c.json",
+            "data/sub/d.csv",
+            "other/e.csv",
+        ] {
+            crate::put_memory_object(&format!("memory://glob/{}", key), "x").unwrap();
+        }
+        let mut found = cache
+            .obstacle
+            .glob("memory://glob/data/*.csv", None)
+            .unwrap();
+        found.sort();
+        assert_eq!(
+            found,
+            vec!["memory://glob/data/a.csv", "memory://glob/data/b.csv"]
+        );
+        let found = crate::glob("memory://glob/other/*", None).unwrap();
+        assert_eq!(found, vec!["memory://glob/other/e.csv"]);
+    }
+
+    #[test]
+    fn test_shared_download_offsets() {
+        let cache = TestCache::new("offsets", CacheConfig::default());
+        let url = "memory://offsets/a.txt";
+        crate::put_memory_object(url, "0123456789").unwrap();
+        let (first, second) = block_on(async {
+            let (first, second) = futures::join!(
+                cache.obstacle.download_file(url),
+                cache.obstacle.download_file(url)
+            );
+            Ok((first?.unwrap(), second?.unwrap()))
+        })
+        .unwrap();
+        // Each caller reads from its own offset.
+        let mut buffer = [0u8; 4];
+        (&first).read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"0123");
+        (&second).read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"0123");
+    }
+}
@@ -0,0 +1,239 @@
+//! Configuration for the local cache.
+//!
+//! The cache root is resolved in order from:
+//! 1. the root set with `CacheConfig::with_root()`,
+//! 2. the `OBSTACLE_CACHE_DIR` environment variable,
+//! 3. `$XDG_CACHE_HOME/obstinate`,
+//! 4. `~/.cache/obstinate`.
+//!
+//! By default the cache grows without limits, use `with_max_size()` and `with_max_files()` to set a budget.
+
+use crate::cache_key::{default_key_strategy, CacheKeyStrategy};
+use crate::context::Obstacle;
+use crate::err::{obstinate_err, ObstacleError};
+use crate::integrity::ChecksumAlgorithm;
+use home::home_dir;
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The default size of the parts downloaded concurrently.
+pub const DEFAULT_PART_SIZE: usize = 16 * 1024 * 1024;
+
+/// The default number of parts downloaded concurrently.
+pub const DEFAULT_CONCURRENCY: usize = 8;
+
+/// The environment variable used to override the cache root.
+pub const CACHE_DIR_ENV: &str = "OBSTACLE_CACHE_DIR";
+
+/// How the cache uses the cloud.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Check the cached content against the cloud and download the objects that are not cached.
+    #[default]
+    Online,
+    /// Only serve the content already cached, without any network access.
+    Offline,
+}
+
+#[derive(Clone, Debug, Default)]
+/// Options for the local cache.
+pub struct CacheConfig {
+    root: Option<PathBuf>,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+    part_size: Option<usize>,
+    concurrency: Option<usize>,
+    ttl: Option<Duration>,
+    policy: CachePolicy,
+    key_strategies: Vec<(String, Arc<dyn CacheKeyStrategy>)>,
+    checksum: Option<ChecksumAlgorithm>,
+    local_copy: bool,
+}
+
+impl CacheConfig {
+    /// Set the directory where the cached files are saved.
+    pub fn with_root<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    /// Set the maximum number of bytes used by the cache on disk.
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Set the maximum number of objects kept in the cache.
+    pub fn with_max_files(mut self, max_files: usize) -> Self {
+        self.max_files = Some(max_files);
+        self
+    }
+
+    /// Set the size of the parts downloaded concurrently, smaller objects are downloaded with a single request.
+    pub fn with_download_part_size(mut self, part_size: usize) -> Self {
+        self.part_size = Some(part_size);
+        self
+    }
+
+    /// Set the number of parts downloaded concurrently, 1 disables the multi-part downloads.
+    pub fn with_download_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = Some(concurrency);
+        self
+    }
+
+    /// Trust the cached content for the given duration after it was checked against the cloud.
+    /// By default every access revalidates the cached content with a conditional request.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Set how the cache uses the cloud, for example to work offline from the cached content.
+    pub fn with_policy(mut self, policy: CachePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Set how the cache keys are derived for the urls with the given scheme, for example `http`.
+    pub fn with_key_strategy<S: Into<String>>(
+        mut self,
+        scheme: S,
+        strategy: Arc<dyn CacheKeyStrategy>,
+    ) -> Self {
+        let scheme = scheme.into();
+        self.key_strategies
+            .retain(|(existing, _)| *existing != scheme);
+        self.key_strategies.push((scheme, strategy));
+        self
+    }
+
+    /// Verify the cached content with a checksum, the digest is computed after each download and checked on
+    /// every cache hit. By default only the size of the content is checked.
+    pub fn with_checksum(mut self, checksum: ChecksumAlgorithm) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    /// Copy the files of `file://` urls to the cache instead of mapping them in place,
+    /// for example for files on network mounts.
+    pub fn with_local_copy(mut self, local_copy: bool) -> Self {
+        self.local_copy = local_copy;
+        self
+    }
+
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    pub fn max_files(&self) -> Option<usize> {
+        self.max_files
+    }
+
+    pub fn download_part_size(&self) -> usize {
+        self.part_size.unwrap_or(DEFAULT_PART_SIZE).max(1)
+    }
+
+    pub fn download_concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1)
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    pub fn policy(&self) -> CachePolicy {
+        self.policy
+    }
+
+    pub fn checksum(&self) -> Option<ChecksumAlgorithm> {
+        self.checksum
+    }
+
+    pub fn local_copy(&self) -> bool {
+        self.local_copy
+    }
+
+    /// The strategy deriving the cache keys for the urls with the given scheme.
+    pub fn key_strategy(&self, scheme: &str) -> Arc<dyn CacheKeyStrategy> {
+        self.key_strategies
+            .iter()
+            .find(|(existing, _)| existing == scheme)
+            .map(|(_, strategy)| strategy.clone())
+            .unwrap_or_else(|| default_key_strategy(scheme))
+    }
+
+    /// Resolve the directory where the cached files are saved.
+    pub fn root(&self) -> Result<PathBuf, ObstacleError> {
+        _resolve_root(
+            self.root.as_deref(),
+            env::var_os(CACHE_DIR_ENV),
+            env::var_os("XDG_CACHE_HOME"),
+            home_dir(),
+        )
+    }
+}
+
+fn _resolve_root(
+    root: Option<&Path>,
+    cache_dir: Option<OsString>,
+    xdg_cache_home: Option<OsString>,
+    home: Option<PathBuf>,
+) -> Result<PathBuf, ObstacleError> {
+    if let Some(root) = root {
+        return Ok(root.to_path_buf());
+    }
+    if let Some(cache_dir) = cache_dir.filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(cache_dir));
+    }
+    if let Some(xdg_cache_home) = xdg_cache_home.filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(xdg_cache_home).join("obstinate"));
+    }
+    if let Some(home) = home.filter(|dir| !dir.as_os_str().is_empty()) {
+        return Ok(home.join(".cache/obstinate"));
+    }
+    obstinate_err(format!(
+        "cannot resolve the cache directory, use CacheConfig::with_root() or set {}",
+        CACHE_DIR_ENV
+    ))
+}
+
+/// Set the configuration used by the cache, replaces any previous configuration.
+pub fn set_cache_config(config: CacheConfig) {
+    Obstacle::global().set_cache_config(config);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve_root() {
+        let home = Some(PathBuf::from("/home/user"));
+        assert_eq!(
+            _resolve_root(
+                Some(Path::new("/nvme")),
+                Some("/env".into()),
+                None,
+                home.clone()
+            )
+            .unwrap(),
+            PathBuf::from("/nvme")
+        );
+        assert_eq!(
+            _resolve_root(None, Some("/env".into()), Some("/xdg".into()), home.clone()).unwrap(),
+            PathBuf::from("/env")
+        );
+        assert_eq!(
+            _resolve_root(None, Some("".into()), Some("/xdg".into()), home.clone()).unwrap(),
+            PathBuf::from("/xdg/obstinate")
+        );
+        assert_eq!(
+            _resolve_root(None, None, None, home).unwrap(),
+            PathBuf::from("/home/user/.cache/obstinate")
+        );
+        assert!(_resolve_root(None, None, None, None).is_err());
+    }
+}
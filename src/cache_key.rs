@@ -0,0 +1,287 @@
+//! Strategies deriving the cache key of a version of an object.
+//!
+//! The cached content of an object is saved as `content_<key>`, a new key means a new version of the object
+//! and triggers a download. The strategy also builds the conditional request used to revalidate a cached version.
+//!
+//! The strategy is chosen per url scheme, see `CacheConfig::with_key_strategy()`. By default the cloud stores
+//! use the e-tag, local files and http servers use the modification time and the size. Many http servers omit
+//! the e-tag or compute it per response.
+
+use crate::err::ObstacleError;
+use chrono::{DateTime, Utc};
+use object_store::{GetOptions, ObjectMeta};
+use sha2::{Digest, Sha256};
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// A version of an object available in the cache.
+#[derive(Clone, Debug)]
+pub struct CachedVersion {
+    /// The key the content was saved with.
+    pub key: String,
+    /// The modification time of the object when it was last checked against the cloud.
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+/// Derive the cache key of an object and revalidate the cached versions.
+pub trait CacheKeyStrategy: Debug + Send + Sync {
+    /// The key for the version of the object described by the metadata.
+    /// Return None when the key can only be derived from the content, see `content_key()`.
+    fn key(&self, meta: &ObjectMeta) -> Option<String>;
+
+    /// The options of a get request failing with `NotModified` as long as the cached version is current.
+    fn revalidation(&self, cached: &CachedVersion) -> GetOptions;
+
+    /// The key derived from the downloaded content, the SHA-256 of the content by default.
+    fn content_key(&self, file: &mut File) -> Result<String, ObstacleError> {
+        let mut hasher = Sha256::new();
+        io::copy(file, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+}
+
+/// The key used when the metadata is missing the preferred identifier.
+pub(crate) fn last_modified_key(meta: &ObjectMeta) -> String {
+    format!("{}-{}", meta.last_modified.timestamp_millis(), meta.size)
+}
+
+/// True when the cached key was derived with `last_modified_key()` from the recorded modification time.
+fn _is_last_modified_key(cached: &CachedVersion) -> bool {
+    let prefix = match cached.last_modified {
+        Some(last_modified) => format!("{}-", last_modified.timestamp_millis()),
+        None => return false,
+    };
+    cached
+        .key
+        .strip_prefix(&prefix)
+        .is_some_and(|size| !size.is_empty() && size.bytes().all(|byte| byte.is_ascii_digit()))
+}
+
+/// The options of a get request for a range of the version of the object described by the metadata.
+///
+/// The request fails with `Precondition` once the object changed, checked with the e-tag when the store
+/// provides one and with the modification time otherwise.
+pub(crate) fn version_range(meta: &ObjectMeta, range: Range<usize>) -> GetOptions {
+    GetOptions {
+        if_match: meta.e_tag.clone(),
+        if_unmodified_since: meta.e_tag.is_none().then_some(meta.last_modified),
+        version: meta.version.clone(),
+        range: Some(range.into()),
+        ..GetOptions::default()
+    }
+}
+
+fn _if_modified_since(cached: &CachedVersion) -> GetOptions {
+    GetOptions {
+        if_modified_since: cached.last_modified,
+        ..GetOptions::default()
+    }
+}
+
+/// Use the e-tag of the object, revalidated with `If-None-Match`.
+///
+/// Objects returned without e-tag fall back to the modification time and the size, they are revalidated
+/// with `If-Modified-Since`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ETagKey;
+
+impl CacheKeyStrategy for ETagKey {
+    fn key(&self, meta: &ObjectMeta) -> Option<String> {
+        Some(
+            meta.e_tag
+                .clone()
+                .unwrap_or_else(|| last_modified_key(meta)),
+        )
+    }
+
+    fn revalidation(&self, cached: &CachedVersion) -> GetOptions {
+        if _is_last_modified_key(cached) {
+            return _if_modified_since(cached);
+        }
+        GetOptions {
+            if_none_match: Some(cached.key.clone()),
+            ..GetOptions::default()
+        }
+    }
+}
+
+/// Use the version of the object, for stores with versioning enabled, revalidated with the modification time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VersionKey;
+
+impl CacheKeyStrategy for VersionKey {
+    fn key(&self, meta: &ObjectMeta) -> Option<String> {
+        Some(
+            meta.version
+                .clone()
+                .unwrap_or_else(|| last_modified_key(meta)),
+        )
+    }
+
+    fn revalidation(&self, cached: &CachedVersion) -> GetOptions {
+        _if_modified_since(cached)
+    }
+}
+
+/// Use the modification time and the size of the object, revalidated with `If-Modified-Since`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LastModifiedKey;
+
+impl CacheKeyStrategy for LastModifiedKey {
+    fn key(&self, meta: &ObjectMeta) -> Option<String> {
+        Some(last_modified_key(meta))
+    }
+
+    fn revalidation(&self, cached: &CachedVersion) -> GetOptions {
+        _if_modified_since(cached)
+    }
+}
+
+/// Use a hash of the content, for stores without reliable metadata.
+///
+/// Changes are detected with the modification time, the content downloaded again is only saved when its hash differs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ContentHashKey;
+
+impl CacheKeyStrategy for ContentHashKey {
+    fn key(&self, _meta: &ObjectMeta) -> Option<String> {
+        None
+    }
+
+    fn revalidation(&self, cached: &CachedVersion) -> GetOptions {
+        _if_modified_since(cached)
+    }
+}
+
+/// The strategy used for the scheme when none is configured.
+pub(crate) fn default_key_strategy(scheme: &str) -> Arc<dyn CacheKeyStrategy> {
+    match scheme {
+        "file" | "http" | "https" => Arc::new(LastModifiedKey),
+        _ => Arc::new(ETagKey),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use object_store::path::Path;
+    use std::io::Write;
+
+    fn meta(e_tag: Option<&str>, version: Option<&str>) -> ObjectMeta {
+        ObjectMeta {
+            location: Path::from("a/b.parquet"),
+            last_modified: DateTime::parse_from_rfc3339("2023-07-01T10:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            size: 1234,
+            e_tag: e_tag.map(String::from),
+            version: version.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(
+            ETagKey.key(&meta(Some("\"abc\""), None)),
+            Some("\"abc\"".into())
+        );
+        assert_eq!(
+            ETagKey.key(&meta(None, None)),
+            Some("1688205600000-1234".into())
+        );
+        assert_eq!(
+            VersionKey.key(&meta(Some("abc"), Some("v2"))),
+            Some("v2".into())
+        );
+        assert_eq!(
+            VersionKey.key(&meta(Some("abc"), None)),
+            Some("1688205600000-1234".into())
+        );
+        assert_eq!(
+            LastModifiedKey.key(&meta(Some("abc"), None)),
+            Some("1688205600000-1234".into())
+        );
+        assert_eq!(ContentHashKey.key(&meta(Some("abc"), None)), None);
+    }
+
+    #[test]
+    fn test_revalidation() {
+        let cached = CachedVersion {
+            key: "abc".into(),
+            last_modified: Some(meta(None, None).last_modified),
+        };
+        let options = ETagKey.revalidation(&cached);
+        assert_eq!(options.if_none_match, Some("abc".into()));
+        assert_eq!(options.if_modified_since, None);
+        let options = LastModifiedKey.revalidation(&cached);
+        assert_eq!(options.if_none_match, None);
+        assert_eq!(options.if_modified_since, cached.last_modified);
+
+        // The key of an object without e-tag is not sent as an e-tag.
+        let fallback = CachedVersion {
+            key: ETagKey.key(&meta(None, None)).unwrap(),
+            last_modified: cached.last_modified,
+        };
+        let options = ETagKey.revalidation(&fallback);
+        assert_eq!(options.if_none_match, None);
+        assert_eq!(options.if_modified_since, cached.last_modified);
+        let other_time = CachedVersion {
+            key: "1000-1234".into(),
+            ..fallback
+        };
+        assert_eq!(
+            ETagKey.revalidation(&other_time).if_none_match,
+            Some("1000-1234".into())
+        );
+    }
+
+    #[test]
+    fn test_version_range() {
+        let options = version_range(&meta(Some("abc"), Some("v2")), 10..20);
+        assert_eq!(options.if_match, Some("abc".into()));
+        assert_eq!(options.if_unmodified_since, None);
+        assert_eq!(options.version, Some("v2".into()));
+        let options = version_range(&meta(None, None), 10..20);
+        assert_eq!(options.if_match, None);
+        assert_eq!(
+            options.if_unmodified_since,
+            Some(meta(None, None).last_modified)
+        );
+    }
+
+    #[test]
+    fn test_default_key_strategy() {
+        let meta = meta(Some("\"abc\""), None);
+        assert_eq!(
+            default_key_strategy("s3").key(&meta),
+            Some("\"abc\"".into())
+        );
+        for scheme in ["file", "http", "https"] {
+            assert_eq!(
+                default_key_strategy(scheme).key(&meta),
+                Some("1688205600000-1234".into())
+            );
+        }
+    }
+
+    #[test]
+    fn test_content_key() {
+        let path =
+            std::env::temp_dir().join(format!("obstacle_content_key_{}", std::process::id()));
+        File::create(&path)
+            .unwrap()
+            .write_all(b"hello world")
+            .unwrap();
+        let key = ContentHashKey
+            .content_key(&mut File::open(&path).unwrap())
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            key,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+    }
+}
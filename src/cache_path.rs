@@ -0,0 +1,283 @@
+//! Encode the parts of a cloud location into local path components.
+//!
+//! Each part, the scheme, the bucket and the full key, becomes a single path component. The bytes outside of
+//! `[a-z0-9_.-]` are escaped as `%XX`, including `/` and a leading `.`, so a key can neither escape the cache root
+//! nor collide with another key or with the files the cache saves next to it. Upper case letters are kept on
+//! case sensitive file systems. The empty string is encoded as `%`.
+//!
+//! Windows strips a trailing `.` and opens a device for the reserved names, `con`, `nul.txt` or `LPT1` for
+//! example, a trailing `.` and the first byte of a reserved name are escaped as well. Trailing spaces are always
+//! escaped.
+//!
+//! The files saved for each version of an object are named `<kind>_<encoded key>`, e-tags are not valid file names
+//! either, weak e-tags start with `W/`.
+//!
+//! The bucket of a service selected by the cloud options, an S3 compatible endpoint or an Azure account, is
+//! suffixed with `~` and a hash of the settings, see `encode_scoped_component()`. Buckets with the same name on
+//! different services are cached separately.
+//!
+//! Components longer than `MAX_COMPONENT_LEN` are shortened and suffixed with `~` and the SHA-256 of the original,
+//! they cannot be decoded, the url of such objects is recovered from their metadata.
+
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// Keep the encoded components well under the 255 bytes allowed by most file systems.
+const MAX_COMPONENT_LEN: usize = 200;
+
+/// Case insensitive file systems would map keys differing only by case to the same path.
+const KEEP_UPPERCASE: bool = !cfg!(any(windows, target_os = "macos"));
+
+fn _is_kept(byte: u8, index: usize) -> bool {
+    byte.is_ascii_lowercase()
+        || byte.is_ascii_digit()
+        || byte == b'-'
+        || byte == b'_'
+        || (byte == b'.' && index > 0)
+        || (KEEP_UPPERCASE && byte.is_ascii_uppercase())
+}
+
+/// Windows device names, reserved in any case and with any extension.
+const RESERVED_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+fn _is_reserved(value: &str) -> bool {
+    let stem = value.split('.').next().unwrap_or_default();
+    RESERVED_NAMES
+        .iter()
+        .any(|name| stem.eq_ignore_ascii_case(name))
+}
+
+/// Encode the value into a single path component.
+pub fn encode_component(value: &str) -> String {
+    if value.is_empty() {
+        return "%".into();
+    }
+    let reserved = _is_reserved(value);
+    let last = value.len() - 1;
+    let mut encoded = String::with_capacity(value.len());
+    for (index, byte) in value.bytes().enumerate() {
+        let escaped = (index == 0 && reserved) || (index == last && byte == b'.');
+        if !escaped && _is_kept(byte, index) {
+            encoded.push(byte as char);
+        } else {
+            write!(encoded, "%{:02X}", byte).unwrap();
+        }
+    }
+    if encoded.len() <= MAX_COMPONENT_LEN {
+        return encoded;
+    }
+    // Keep a readable prefix, without splitting an escape sequence.
+    let mut end = MAX_COMPONENT_LEN - 65;
+    while encoded[..end].ends_with('%') || encoded[..end - 1].ends_with('%') {
+        end -= 1;
+    }
+    format!("{}~{:x}", &encoded[..end], Sha256::digest(value.as_bytes()))
+}
+
+/// Decode a path component, None when the component was not produced by `encode_component()` or was shortened.
+pub fn decode_component(component: &str) -> Option<String> {
+    if component == "%" {
+        return Some(String::new());
+    }
+    let mut decoded = Vec::with_capacity(component.len());
+    let mut bytes = component.bytes();
+    let mut last_escaped = false;
+    while let Some(byte) = bytes.next() {
+        last_escaped = byte == b'%';
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else if _is_kept(byte, decoded.len()) || byte.is_ascii_uppercase() {
+            decoded.push(byte);
+        } else {
+            return None;
+        }
+    }
+    let decoded = String::from_utf8(decoded).ok()?;
+    if (decoded.ends_with('.') && !last_escaped)
+        || (_is_reserved(&decoded) && !component.starts_with('%'))
+    {
+        return None;
+    }
+    Some(decoded)
+}
+
+/// The length of the hash suffixed to scoped components.
+const SCOPE_HASH_LEN: usize = 16;
+
+/// Encode the value into a single path component, suffixed with a hash of the scope when present.
+pub fn encode_scoped_component(value: &str, scope: Option<&str>) -> String {
+    let encoded = encode_component(value);
+    match scope {
+        Some(scope) => {
+            let hash = format!("{:x}", Sha256::digest(scope.as_bytes()));
+            format!("{}~{}", encoded, &hash[..SCOPE_HASH_LEN])
+        }
+        None => encoded,
+    }
+}
+
+/// Decode a component produced by `encode_scoped_component()`, the scope cannot be recovered.
+pub fn decode_scoped_component(component: &str) -> Option<String> {
+    match component.rsplit_once('~') {
+        Some((encoded, hash))
+            if hash.len() == SCOPE_HASH_LEN
+                && hash.bytes().all(|byte| byte.is_ascii_hexdigit()) =>
+        {
+            decode_component(encoded)
+        }
+        _ => decode_component(component),
+    }
+}
+
+/// The name of a file saved by the cache for a version of an object, `<kind>_<encoded key>`.
+pub fn cache_file_name(kind: &str, key: &str) -> String {
+    format!("{}_{}", kind, encode_component(key))
+}
+
+/// Split the name of a file saved by the cache into its kind and its key.
+pub fn parse_cache_file_name(file_name: &str) -> Option<(&str, String)> {
+    let (kind, encoded) = file_name.split_once('_')?;
+    Some((kind, decode_component(encoded)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::{Component, Path};
+
+    const HOSTILE_KEYS: &[&str] = &[
+        "",
+        ".",
+        "..",
+        "../../etc/passwd",
+        "a/../../b",
+        "/absolute/path",
+        "a/b",
+        "a%2Fb",
+        "a/b/",
+        "a//b",
+        "lock",
+        "content_1234",
+        "meta_1234",
+        ".hidden",
+        "C:\\Windows\\system32",
+        "con",
+        "CON",
+        "nul.txt",
+        "aux.tar.gz",
+        "com1",
+        "lpt9.",
+        "a.",
+        "a..",
+        "a ",
+        "nul\0byte",
+        "tab\tnew\nline",
+        "spaces and ~tilde~",
+        "unicode/ünïcødé/日本",
+        "percent%",
+        "%",
+    ];
+
+    #[test]
+    fn test_hostile_keys() {
+        let mut seen = std::collections::BTreeSet::new();
+        for key in HOSTILE_KEYS {
+            let encoded = encode_component(key);
+            // A single normal component, the path stays under its parent.
+            let mut components = Path::new(&encoded).components();
+            assert!(
+                matches!(components.next(), Some(Component::Normal(_))),
+                "{:?} -> {:?}",
+                key,
+                encoded
+            );
+            assert!(components.next().is_none(), "{:?} -> {:?}", key, encoded);
+            assert!(!encoded.contains('/') && !encoded.contains('\\'));
+            // Reversible and without collisions.
+            assert_eq!(decode_component(&encoded).as_deref(), Some(*key));
+            assert!(seen.insert(encoded.clone()), "collision on {:?}", encoded);
+        }
+    }
+
+    #[test]
+    fn test_readable() {
+        assert_eq!(
+            encode_component("data/2023/b.parquet"),
+            "data%2F2023%2Fb.parquet"
+        );
+        assert_eq!(encode_component("my-bucket"), "my-bucket");
+        assert_eq!(encode_component(".."), "%2E%2E");
+    }
+
+    #[test]
+    fn test_windows_names() {
+        assert_eq!(encode_component("a."), "a%2E");
+        assert_eq!(encode_component("a.b."), "a.b%2E");
+        assert_eq!(encode_component("a "), "a%20");
+        assert_eq!(encode_component("con"), "%63on");
+        assert_eq!(encode_component("nul.txt"), "%6Eul.txt");
+        assert_eq!(encode_component("lpt9"), "%6Cpt9");
+        assert_eq!(encode_component("console"), "console");
+        assert_eq!(encode_component("com10"), "com10");
+        assert_eq!(encode_component("a.con"), "a.con");
+        // The unescaped forms are not produced by the encoder.
+        assert_eq!(decode_component("a."), None);
+        assert_eq!(decode_component("con"), None);
+        assert_eq!(decode_component("NUL.txt"), None);
+    }
+
+    #[test]
+    fn test_long_keys() {
+        let long = "a/".repeat(300);
+        let other = format!("{}b", long);
+        let encoded = encode_component(&long);
+        assert!(encoded.len() <= MAX_COMPONENT_LEN);
+        assert!(encoded.contains('~'));
+        assert_ne!(encoded, encode_component(&other));
+        assert_eq!(decode_component(&encoded), None);
+        // The prefix does not end with a partial escape sequence.
+        let prefix = encoded.split('~').next().unwrap();
+        assert!(decode_component(prefix).is_some());
+    }
+
+    #[test]
+    fn test_cache_file_name() {
+        let name = cache_file_name("content", "W/\"1234\"");
+        assert_eq!(name, "content_W%2F%221234%22");
+        assert_eq!(
+            parse_cache_file_name(&name),
+            Some(("content", "W/\"1234\"".into()))
+        );
+        assert_eq!(parse_cache_file_name("lock"), None);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert_eq!(decode_component("a/b"), None);
+        assert_eq!(decode_component("%2"), None);
+        assert_eq!(decode_component("%zz"), None);
+        assert_eq!(decode_component("%FF"), None);
+    }
+
+    #[test]
+    fn test_scoped_component() {
+        let minio = encode_scoped_component("bucket", Some("aws_endpoint=http://localhost:9000"));
+        let aws = encode_scoped_component("bucket", None);
+        assert_eq!(aws, "bucket");
+        assert!(minio.starts_with("bucket~") && minio.len() == "bucket~".len() + SCOPE_HASH_LEN);
+        assert_ne!(
+            minio,
+            encode_scoped_component("bucket", Some("aws_endpoint=http://other:9000"))
+        );
+        assert_eq!(decode_scoped_component(&minio).as_deref(), Some("bucket"));
+        assert_eq!(decode_scoped_component(&aws).as_deref(), Some("bucket"));
+        assert_eq!(
+            decode_scoped_component(&encode_component(&"b".repeat(300))),
+            None
+        );
+    }
+}
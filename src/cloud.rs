@@ -1,8 +1,14 @@
+#[cfg(feature = "aws")]
+use crate::aws_profile;
 use crate::{
+    context::Obstacle,
     err::{obstinate_err, ObstacleError},
     glob::CloudLocation,
 };
+#[cfg(feature = "aws")]
+use log::debug;
 use std::str::FromStr;
+use std::sync::Arc;
 
 #[cfg(feature = "aws")]
 use object_store::aws::AmazonS3Builder;
@@ -16,7 +22,11 @@ use object_store::azure::MicrosoftAzureBuilder;
 use object_store::gcp::GoogleCloudStorageBuilder;
 #[cfg(feature = "gcp")]
 pub use object_store::gcp::GoogleConfigKey;
+#[cfg(feature = "http")]
+use object_store::http::HttpBuilder;
 use object_store::local::LocalFileSystem;
+#[cfg(feature = "http")]
+use object_store::ClientConfigKey;
 use object_store::ObjectStore;
 #[cfg(feature = "serde-lazy")]
 use serde::{Deserialize, Serialize};
@@ -32,7 +42,7 @@ use url::Url;
 #[allow(dead_code)]
 type Configs<T> = Vec<(T, String)>;
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "serde-lazy", derive(Serialize, Deserialize))]
 /// Options to connect to various cloud providers.
 pub struct CloudOptions {
@@ -42,6 +52,8 @@ pub struct CloudOptions {
     azure: Option<Configs<AzureConfigKey>>,
     #[cfg(feature = "gcp")]
     gcp: Option<Configs<GoogleConfigKey>>,
+    #[cfg(feature = "http")]
+    http: Option<Configs<ClientConfigKey>>,
 }
 
 #[allow(dead_code)]
@@ -70,6 +82,8 @@ pub enum CloudType {
     Azure,
     File,
     Gcp,
+    Http,
+    Memory,
 }
 
 impl FromStr for CloudType {
@@ -83,6 +97,8 @@ impl FromStr for CloudType {
             "az" | "adl" | "abfs" => Self::Azure,
             "gs" | "gcp" => Self::Gcp,
             "file" => Self::File,
+            "http" | "https" => Self::Http,
+            "memory" => Self::Memory,
             _ => return obstinate_err(format!("unknown url scheme {}", parsed.scheme())),
         })
     }
@@ -193,18 +209,134 @@ impl CloudOptions {
             .map_err(ObstacleError::from_err)
     }
 
+    /// Set the configuration of the http client for HTTP and WebDAV servers, optional.
+    #[cfg(feature = "http")]
+    pub fn with_http<I: IntoIterator<Item = (ClientConfigKey, impl Into<String>)>>(
+        mut self,
+        configs: I,
+    ) -> Self {
+        self.http = Some(
+            configs
+                .into_iter()
+                .map(|(k, v)| (k, v.into()))
+                .collect::<Configs<ClientConfigKey>>(),
+        );
+        self
+    }
+
+    /// Build the ObjectStore implementation for the HTTP or WebDAV server at the base url.
+    /// Plain http is allowed for `http://` urls.
+    #[cfg(feature = "http")]
+    pub fn build_http(&self, base_url: &str) -> Result<impl ObjectStore, ObstacleError> {
+        let mut builder = HttpBuilder::new();
+        if base_url.starts_with("http://") {
+            builder = builder.with_config(ClientConfigKey::AllowHttp, "true");
+        }
+        for (key, value) in self.http.iter().flatten() {
+            builder = builder.with_config(*key, value);
+        }
+        builder
+            .with_url(base_url)
+            .build()
+            .map_err(ObstacleError::from_err)
+    }
+
+    /// The settings selecting the service holding the buckets of the url, the endpoint and the account.
+    /// None for the default service of the provider.
+    #[allow(unused_mut)]
+    pub(crate) fn service_scope(&self, url: &str) -> Option<String> {
+        let mut settings = Vec::<String>::new();
+        match CloudType::from_str(url).ok()? {
+            #[cfg(feature = "aws")]
+            CloudType::Aws => {
+                settings = _scope_settings(self.aws.as_ref(), &[AmazonS3ConfigKey::Endpoint])
+            }
+            #[cfg(feature = "azure")]
+            CloudType::Azure => {
+                settings = _scope_settings(
+                    self.azure.as_ref(),
+                    &[AzureConfigKey::AccountName, AzureConfigKey::Endpoint],
+                )
+            }
+            _ => {}
+        }
+        (!settings.is_empty()).then(|| settings.join("\n"))
+    }
+
+    /// Read the configuration of the enabled providers from the environment.
+    ///
+    /// Like the `from_env()` of the object_store builders, the `AWS_*`, `AZURE_*` and `GOOGLE_*` variables are
+    /// used, along with `MSI_ENDPOINT` for Azure and `SERVICE_ACCOUNT` for GCP. The AWS settings start with the
+    /// profile selected by `AWS_PROFILE`, or the `default` profile, when the profile files define it.
+    /// The variables are read once, the options do not change with the environment.
+    pub fn from_env() -> Self {
+        #[allow(unused_mut)]
+        let mut options = Self::default();
+        #[cfg(feature = "aws")]
+        {
+            let profile = aws_profile::selected_profile();
+            let mut aws = match aws_profile::read_profile(&profile) {
+                Ok(configs) => configs.unwrap_or_default(),
+                Err(err) => {
+                    debug!("ignoring the aws profile {}: {}", profile, err);
+                    vec![]
+                }
+            };
+            aws.extend(_env_configs::<AmazonS3ConfigKey>("AWS_"));
+            options.aws = Some(aws);
+        }
+        #[cfg(feature = "azure")]
+        {
+            let mut azure = _env_configs::<AzureConfigKey>("AZURE_");
+            if let Ok(msi_endpoint) = std::env::var("MSI_ENDPOINT") {
+                azure.push((AzureConfigKey::MsiEndpoint, msi_endpoint));
+            }
+            options.azure = Some(azure);
+        }
+        #[cfg(feature = "gcp")]
+        {
+            let mut gcp = vec![];
+            if let Ok(service_account) = std::env::var("SERVICE_ACCOUNT") {
+                gcp.push((GoogleConfigKey::ServiceAccount, service_account));
+            }
+            gcp.extend(_env_configs::<GoogleConfigKey>("GOOGLE_"));
+            options.gcp = Some(gcp);
+        }
+        options
+    }
+
+    /// Add the settings of an AWS profile from `~/.aws/config` and `~/.aws/credentials`.
+    /// The settings of the profile replace the ones set before.
+    #[cfg(feature = "aws")]
+    pub fn with_aws_profile(mut self, profile: &str) -> Result<Self, ObstacleError> {
+        let configs = aws_profile::read_profile(profile)?
+            .ok_or_else(|| ObstacleError::new(format!("aws profile {} not found", profile)))?;
+        self.aws.get_or_insert_with(Vec::new).extend(configs);
+        Ok(self)
+    }
+
     /// Parse a configuration from a Hashmap. This is the interface from Python.
-    #[allow(unused_variables)]
     pub fn from_untyped_config<I: IntoIterator<Item = (impl AsRef<str>, impl Into<String>)>>(
         url: &str,
         config: I,
+    ) -> Result<Self, ObstacleError> {
+        Self::default().with_untyped_config(url, config)
+    }
+
+    /// Add an untyped configuration for the provider of the url.
+    /// The parsed settings replace the ones set before.
+    #[allow(unused_variables, unused_mut)]
+    pub fn with_untyped_config<I: IntoIterator<Item = (impl AsRef<str>, impl Into<String>)>>(
+        mut self,
+        url: &str,
+        config: I,
     ) -> Result<Self, ObstacleError> {
         match CloudType::from_str(url)? {
             CloudType::Aws => {
                 #[cfg(feature = "aws")]
                 {
-                    parsed_untyped_config::<AmazonS3ConfigKey, _>(config)
-                        .map(|aws| Self::default().with_aws(aws))
+                    let aws = parsed_untyped_config::<AmazonS3ConfigKey, _>(config)?;
+                    self.aws.get_or_insert_with(Vec::new).extend(aws);
                 }
                 #[cfg(not(feature = "aws"))]
                 {
@@ -214,30 +346,84 @@ impl CloudOptions {
             CloudType::Azure => {
                 #[cfg(feature = "azure")]
                 {
-                    parsed_untyped_config::<AzureConfigKey, _>(config)
-                        .map(|azure| Self::default().with_azure(azure))
+                    let azure = parsed_untyped_config::<AzureConfigKey, _>(config)?;
+                    self.azure.get_or_insert_with(Vec::new).extend(azure);
                 }
                 #[cfg(not(feature = "azure"))]
                 {
                     return obstinate_err("'azure' feature is not enabled");
                 }
             }
-            CloudType::File => Ok(Self::default()),
+            CloudType::File | CloudType::Memory => {}
             CloudType::Gcp => {
                 #[cfg(feature = "gcp")]
                 {
-                    parsed_untyped_config::<GoogleConfigKey, _>(config)
-                        .map(|gcp| Self::default().with_gcp(gcp))
+                    let gcp = parsed_untyped_config::<GoogleConfigKey, _>(config)?;
+                    self.gcp.get_or_insert_with(Vec::new).extend(gcp);
                 }
                 #[cfg(not(feature = "gcp"))]
                 {
                     return obstinate_err("'gcp' feature is not enabled");
                 }
             }
+            CloudType::Http => {
+                #[cfg(feature = "http")]
+                {
+                    let http = parsed_untyped_config::<ClientConfigKey, _>(config)?;
+                    self.http.get_or_insert_with(Vec::new).extend(http);
+                }
+                #[cfg(not(feature = "http"))]
+                {
+                    return obstinate_err("'http' feature is not enabled");
+                }
+            }
         }
+        Ok(self)
     }
 }
 
+/// The values of the keys as `key=value`, the last value of a key is used like in the builders.
+#[allow(dead_code)]
+fn _scope_settings<T: AsRef<str> + PartialEq>(
+    configs: Option<&Configs<T>>,
+    keys: &[T],
+) -> Vec<String> {
+    keys.iter()
+        .filter_map(|key| {
+            let (_, value) = configs?
+                .iter()
+                .rev()
+                .find(|(candidate, _)| candidate == key)?;
+            Some(format!("{}={}", key.as_ref(), value))
+        })
+        .collect()
+}
+
+/// The configuration set in the environment variables with the prefix, sorted by variable name.
+#[allow(dead_code)]
+fn _env_configs<T: FromStr>(prefix: &str) -> Configs<T> {
+    let variables = std::env::vars_os()
+        .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)));
+    _variables_configs(variables, prefix)
+}
+
+/// The configuration set in the variables with the prefix, sorted by variable name.
+#[allow(dead_code)]
+fn _variables_configs<T: FromStr>(
+    variables: impl IntoIterator<Item = (String, String)>,
+    prefix: &str,
+) -> Configs<T> {
+    let mut variables = variables
+        .into_iter()
+        .filter(|(key, _)| key.starts_with(prefix))
+        .collect::<Vec<_>>();
+    variables.sort();
+    variables
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_ascii_lowercase().parse().ok()?, value)))
+        .collect()
+}
+
 #[allow(dead_code)]
 fn err_missing_feature<T>(feature: &str, scheme: &str) -> Result<T, ObstacleError> {
     Err(ObstacleError::new(format!(
@@ -303,20 +489,267 @@ pub fn build(
             #[cfg(not(feature = "azure"))]
             return err_missing_feature("azure", &cloud_location.scheme);
         }
+        CloudType::Http => {
+            // Public servers do not need any configuration.
+            #[cfg(feature = "http")]
+            {
+                let base_url = format!("{}://{}", cloud_location.scheme, cloud_location.bucket);
+                let store = _options
+                    .cloned()
+                    .unwrap_or_default()
+                    .build_http(&base_url)?;
+                Ok::<_, ObstacleError>(Box::new(store) as Box<dyn ObjectStore>)
+            }
+            #[cfg(not(feature = "http"))]
+            return err_missing_feature("http", &cloud_location.scheme);
+        }
+        CloudType::Memory => {
+            #[cfg(feature = "async")]
+            {
+                let store = crate::memory::memory_store(&cloud_location.bucket);
+                Ok::<_, ObstacleError>(Box::new(store) as Box<dyn ObjectStore>)
+            }
+            #[cfg(not(feature = "async"))]
+            return err_missing_feature("async", &cloud_location.scheme);
+        }
     }?;
     Ok((cloud_location, store))
 }
 
-use std::sync::OnceLock;
+/// Like `build()`, but return the store already built for the same scheme, bucket and options.
+///
+/// Without options, the options set for the url are used. The stores are kept by the default instance,
+/// see `Obstacle::store()`.
+pub fn shared_store(
+    url: &str,
+    options: Option<&CloudOptions>,
+) -> Result<(CloudLocation, Arc<dyn ObjectStore>), ObstacleError> {
+    Obstacle::global().store(url, options)
+}
+
+/// Drop the stores built by `shared_store()`, the next calls build new stores.
+pub fn clear_shared_stores() {
+    Obstacle::global().clear_stores();
+}
 
-static mut CLOUD_OPTIONS: OnceLock<CloudOptions> = OnceLock::new();
+/// Options used for the urls matching a pattern.
+#[derive(Clone, Debug)]
+struct OptionsRule {
+    scheme: String,
+    bucket: Option<String>,
+    prefix: String,
+    options: CloudOptions,
+}
 
-pub fn set_cloud_options(options: CloudOptions) {
-    unsafe {
-        CLOUD_OPTIONS.get_or_init(|| options);
+impl OptionsRule {
+    fn is_matching(&self, location: &CloudLocation) -> bool {
+        (self.scheme.is_empty() || self.scheme == location.scheme)
+            && self
+                .bucket
+                .as_ref()
+                .is_none_or(|bucket| *bucket == location.bucket)
+            && location.prefix.starts_with(&self.prefix)
     }
 }
 
-pub fn get_cloud_options() -> Option<&'static CloudOptions> {
-    unsafe { CLOUD_OPTIONS.get() }
+/// Select the cloud options of an url, this allows using several accounts or endpoints in the same process.
+///
+/// The patterns are urls without wildcards: `s3://` matches all the urls with the scheme, `s3://bucket` all the
+/// objects of the bucket and `s3://bucket/data/` the objects with the prefix. The most specific pattern is used,
+/// the last one added on ties.
+#[derive(Clone, Debug, Default)]
+pub struct CloudOptionsResolver {
+    rules: Vec<OptionsRule>,
+}
+
+impl CloudOptionsResolver {
+    /// Use the options for the urls matching the pattern.
+    pub fn with_options(mut self, pattern: &str, options: CloudOptions) -> Self {
+        let (scheme, rest) = pattern.split_once("://").unwrap_or((pattern, ""));
+        let (bucket, prefix) = match rest.split_once('/') {
+            Some((bucket, prefix)) => (Some(bucket), prefix),
+            None if rest.is_empty() => (None, ""),
+            None => (Some(rest), ""),
+        };
+        self.rules.push(OptionsRule {
+            scheme: scheme.to_string(),
+            bucket: bucket.map(String::from),
+            prefix: prefix.to_string(),
+            options,
+        });
+        self
+    }
+
+    /// Use the options for the urls not matching any other pattern.
+    pub fn with_default(self, options: CloudOptions) -> Self {
+        self.with_options("", options)
+    }
+
+    /// The options used for the urls not matching any other pattern, see `with_default()`.
+    pub fn default_options(&self) -> Option<&CloudOptions> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.scheme.is_empty() && rule.bucket.is_none() && rule.prefix.is_empty())
+            .map(|rule| &rule.options)
+    }
+
+    /// The options to use for the url, if any.
+    pub fn resolve(&self, url: &str) -> Option<&CloudOptions> {
+        let location = CloudLocation::new(url).ok()?;
+        self.rules
+            .iter()
+            .filter(|rule| rule.is_matching(&location))
+            .max_by_key(|rule| {
+                (
+                    !rule.scheme.is_empty(),
+                    rule.bucket.is_some(),
+                    rule.prefix.len(),
+                )
+            })
+            .map(|rule| &rule.options)
+    }
+}
+
+/// Use the options for all the urls, replaces the options and the resolver set previously.
+pub fn set_cloud_options(options: CloudOptions) {
+    Obstacle::global().set_cloud_options(options);
+}
+
+/// Select the options of each url with the resolver, replaces the options and the resolver set previously.
+pub fn set_cloud_options_resolver(resolver: CloudOptionsResolver) {
+    Obstacle::global().set_cloud_options_resolver(resolver);
+}
+
+/// The options set with `set_cloud_options()`, used for the urls not matching any other pattern.
+#[deprecated(note = "the options depend on the url, use resolve_cloud_options()")]
+pub fn get_cloud_options() -> Option<CloudOptions> {
+    Obstacle::global().default_cloud_options()
+}
+
+/// The options set for the url with `set_cloud_options()` or `set_cloud_options_resolver()`.
+pub fn resolve_cloud_options(url: &str) -> Option<CloudOptions> {
+    Obstacle::global().resolve_cloud_options(url)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shared_store() {
+        let (_, first) = shared_store("file:///tmp/a.csv", None).unwrap();
+        let (_, second) = shared_store("file:///var/b.csv", None).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[cfg(feature = "aws")]
+    #[test]
+    fn test_resolver() {
+        let region =
+            |region: &str| CloudOptions::default().with_aws([(AmazonS3ConfigKey::Region, region)]);
+        let resolver = CloudOptionsResolver::default()
+            .with_default(region("default"))
+            .with_options("s3://", region("s3"))
+            .with_options("s3://minio", region("minio"))
+            .with_options("s3://minio/data/", region("data"))
+            .with_options("s3://minio/data/", region("latest"));
+        assert_eq!(
+            resolver.resolve("gs://bucket/a.csv"),
+            Some(&region("default"))
+        );
+        assert_eq!(resolver.resolve("s3://bucket/a.csv"), Some(&region("s3")));
+        assert_eq!(resolver.resolve("s3://minio/a.csv"), Some(&region("minio")));
+        assert_eq!(resolver.resolve("s3://minio2/a.csv"), Some(&region("s3")));
+        assert_eq!(
+            resolver.resolve("s3://minio/data/a.csv"),
+            Some(&region("latest"))
+        );
+        assert_eq!(
+            CloudOptionsResolver::default().resolve("s3://bucket/a.csv"),
+            None
+        );
+    }
+
+    #[cfg(feature = "aws")]
+    #[test]
+    fn test_from_env() {
+        let variables = [
+            ("AWS_REGION", "eu-central-1"),
+            ("AWS_OBSTACLE_UNKNOWN", "ignored"),
+            ("AWS_ENDPOINT", "http://localhost:9000"),
+            ("AZURE_STORAGE_ACCOUNT_NAME", "other"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        assert_eq!(
+            _variables_configs::<AmazonS3ConfigKey>(variables, "AWS_"),
+            vec![
+                (AmazonS3ConfigKey::Endpoint, "http://localhost:9000".into()),
+                (AmazonS3ConfigKey::Region, "eu-central-1".into()),
+            ]
+        );
+        // The environment is read in a stable order.
+        assert_eq!(CloudOptions::from_env(), CloudOptions::from_env());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_cloud_type() {
+        assert!(matches!(
+            CloudType::from_str("https://host/a.csv"),
+            Ok(CloudType::Http)
+        ));
+        assert!(matches!(
+            CloudType::from_str("http://host:8080/a.csv"),
+            Ok(CloudType::Http)
+        ));
+        assert!(matches!(
+            CloudType::from_str("memory://test/a.csv"),
+            Ok(CloudType::Memory)
+        ));
+        assert!(CloudType::from_str("ftp://host/a.csv").is_err());
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_http_store() {
+        let (location, _) = build("http://localhost:8080/data/a.csv", None).unwrap();
+        assert_eq!(location.bucket, "localhost:8080");
+        assert_eq!(location.prefix, "data/a.csv");
+        let options =
+            CloudOptions::from_untyped_config("https://host/a.csv", [("timeout", "30s")]).unwrap();
+        assert!(build("https://host/a.csv", Some(&options)).is_ok());
+    }
+
+    #[cfg(feature = "aws")]
+    #[test]
+    fn test_service_scope() {
+        let minio = CloudOptions::default().with_aws([
+            (AmazonS3ConfigKey::Region, "us-east-1"),
+            (AmazonS3ConfigKey::Endpoint, "http://localhost:9000"),
+        ]);
+        assert_eq!(
+            minio.service_scope("s3://bucket/a.csv").as_deref(),
+            Some("aws_endpoint=http://localhost:9000")
+        );
+        assert_eq!(minio.service_scope("file:///tmp/a.csv"), None);
+        let aws = CloudOptions::default().with_aws([(AmazonS3ConfigKey::Region, "us-east-1")]);
+        assert_eq!(aws.service_scope("s3://bucket/a.csv"), None);
+    }
+
+    #[cfg(feature = "aws")]
+    #[test]
+    fn test_with_untyped_config() {
+        let options = CloudOptions::default()
+            .with_aws([(AmazonS3ConfigKey::Endpoint, "http://localhost:9000")])
+            .with_untyped_config("s3://bucket/a.csv", [("aws_endpoint", "http://minio:9000")])
+            .unwrap();
+        assert_eq!(
+            options.service_scope("s3://bucket/a.csv").as_deref(),
+            Some("aws_endpoint=http://minio:9000")
+        );
+        assert!(CloudOptions::default()
+            .with_untyped_config("s3://bucket/a.csv", [("unknown", "value")])
+            .is_err());
+    }
 }
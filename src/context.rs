@@ -0,0 +1,335 @@
+//! The state shared by the operations of the crate: the cloud options, the object stores and the cache configuration.
+//!
+//! The free functions of the crate use a default instance, see `Obstacle::global()`. Separate instances can be
+//! created, for example one per test with its own cache root, or one per set of credentials.
+
+#[cfg(feature = "async")]
+use crate::cache::{self, CacheEntry, CacheUsage};
+#[cfg(feature = "async")]
+use crate::cache_config::CacheConfig;
+use crate::cloud::{build, CloudOptions, CloudOptionsResolver};
+use crate::err::ObstacleError;
+use crate::glob::{self, CloudLocation};
+use crate::mmap::{self, Mmap};
+use crate::runtime::block_on;
+use object_store::ObjectStore;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+/// The stores are reused for all the objects of a bucket, keeping their connection pool and credentials.
+///
+/// The config keys are not ordered, the options are compared through their debug representation which lists
+/// the settings in the order they were given.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct StoreKey {
+    scheme: String,
+    bucket: String,
+    options: Option<String>,
+}
+
+#[derive(Default)]
+struct Inner {
+    cloud_options: RwLock<CloudOptionsResolver>,
+    stores: Mutex<BTreeMap<StoreKey, Arc<dyn ObjectStore>>>,
+    #[cfg(feature = "async")]
+    cache_config: RwLock<CacheConfig>,
+}
+
+/// A handle on the cloud options, the object stores and the cache configuration, cheap to clone.
+#[derive(Clone, Default)]
+pub struct Obstacle {
+    inner: Arc<Inner>,
+}
+
+static GLOBAL: OnceLock<Obstacle> = OnceLock::new();
+
+impl fmt::Debug for Obstacle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Obstacle")
+            .field("cloud_options", &self.inner.cloud_options.read().unwrap())
+            .field("stores", &self.inner.stores.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Obstacle {
+    /// Create an instance without cloud options and with the default cache configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The instance used by the free functions of the crate.
+    pub fn global() -> &'static Obstacle {
+        GLOBAL.get_or_init(Obstacle::new)
+    }
+
+    /// Use the options for all the urls.
+    pub fn with_cloud_options(self, options: CloudOptions) -> Self {
+        self.set_cloud_options(options);
+        self
+    }
+
+    /// Select the options of each url with the resolver.
+    pub fn with_cloud_options_resolver(self, resolver: CloudOptionsResolver) -> Self {
+        self.set_cloud_options_resolver(resolver);
+        self
+    }
+
+    /// Use the cache configuration.
+    #[cfg(feature = "async")]
+    pub fn with_cache_config(self, config: CacheConfig) -> Self {
+        self.set_cache_config(config);
+        self
+    }
+
+    /// Use the options for all the urls, replaces the options and the resolver set previously.
+    pub fn set_cloud_options(&self, options: CloudOptions) {
+        self.set_cloud_options_resolver(CloudOptionsResolver::default().with_default(options));
+    }
+
+    /// Select the options of each url with the resolver, replaces the options and the resolver set previously.
+    ///
+    /// The stores built with the previous options are dropped, this is how credentials are updated.
+    pub fn set_cloud_options_resolver(&self, resolver: CloudOptionsResolver) {
+        *self.inner.cloud_options.write().unwrap() = resolver;
+        self.clear_stores();
+    }
+
+    /// The options used for the urls not matching any other pattern, if any.
+    pub fn default_cloud_options(&self) -> Option<CloudOptions> {
+        self.inner
+            .cloud_options
+            .read()
+            .unwrap()
+            .default_options()
+            .cloned()
+    }
+
+    /// The options to use for the url, if any.
+    pub fn resolve_cloud_options(&self, url: &str) -> Option<CloudOptions> {
+        self.inner
+            .cloud_options
+            .read()
+            .unwrap()
+            .resolve(url)
+            .cloned()
+    }
+
+    /// Set the configuration used by the cache, replaces any previous configuration.
+    #[cfg(feature = "async")]
+    pub fn set_cache_config(&self, config: CacheConfig) {
+        *self.inner.cache_config.write().unwrap() = config;
+    }
+
+    /// The configuration used by the cache.
+    #[cfg(feature = "async")]
+    pub fn cache_config(&self) -> CacheConfig {
+        self.inner.cache_config.read().unwrap().clone()
+    }
+
+    /// The store for the url, built on first use for each scheme, bucket and options.
+    ///
+    /// Without options, the options resolved for the url are used.
+    pub fn store(
+        &self,
+        url: &str,
+        options: Option<&CloudOptions>,
+    ) -> Result<(CloudLocation, Arc<dyn ObjectStore>), ObstacleError> {
+        let options = options.cloned().or_else(|| self.resolve_cloud_options(url));
+        let cloud_location = CloudLocation::new(url)?;
+        let key = StoreKey {
+            scheme: cloud_location.scheme.clone(),
+            bucket: cloud_location.bucket.clone(),
+            options: options.as_ref().map(|options| format!("{:?}", options)),
+        };
+        let mut stores = self.inner.stores.lock().unwrap();
+        if let Some(store) = stores.get(&key) {
+            return Ok((cloud_location, store.clone()));
+        }
+        let (cloud_location, store) = build(url, options.as_ref())?;
+        let store: Arc<dyn ObjectStore> = Arc::from(store);
+        stores.insert(key, store.clone());
+        Ok((cloud_location, store))
+    }
+
+    /// Drop the stores built so far, the next calls build new stores.
+    pub fn clear_stores(&self) {
+        self.inner.stores.lock().unwrap().clear();
+    }
+
+    /// Download a file from the cloud and cache it locally, see `cache::download_file()`.
+    #[cfg(feature = "async")]
+    pub async fn download_file(&self, url: &str) -> Result<Option<File>, ObstacleError> {
+        cache::download(self, url, self.cache_config().policy(), None).await
+    }
+
+    /// Open a local or cloud url, see `open_url()`.
+    pub fn open_url(&self, url: &str) -> Result<Option<File>, ObstacleError> {
+        block_on(self.open_url_async(url))
+    }
+
+    /// Open a local or cloud url, see `open_url()`.
+    pub async fn open_url_async(&self, url: &str) -> Result<Option<File>, ObstacleError> {
+        mmap::open(self, url, None).await
+    }
+
+    /// Create a memory map from a local or cloud url, see `Mmap::from_url()`.
+    pub fn mmap(&self, url: &str) -> Result<Option<Mmap>, ObstacleError> {
+        block_on(self.mmap_async(url))
+    }
+
+    /// Create a memory map from a local or cloud url, see `Mmap::from_url()`.
+    pub async fn mmap_async(&self, url: &str) -> Result<Option<Mmap>, ObstacleError> {
+        Mmap::open(self, url, None).await
+    }
+
+    /// Create a memory map from a cloud url without downloading the content, see `Mmap::from_url_sparse()`.
+    #[cfg(all(feature = "async", unix))]
+    pub fn mmap_sparse(&self, url: &str) -> Result<Option<Mmap>, ObstacleError> {
+        block_on(self.mmap_sparse_async(url))
+    }
+
+    /// Create a memory map from a cloud url without downloading the content, see `Mmap::from_url_sparse()`.
+    #[cfg(all(feature = "async", unix))]
+    pub async fn mmap_sparse_async(&self, url: &str) -> Result<Option<Mmap>, ObstacleError> {
+        Mmap::open_sparse(self, url).await
+    }
+
+    /// List files matching the pattern, see `glob()`.
+    pub fn glob(
+        &self,
+        url: &str,
+        cloud_options: Option<&CloudOptions>,
+    ) -> Result<Vec<String>, ObstacleError> {
+        block_on(self.glob_async(url, cloud_options))
+    }
+
+    /// List files matching the pattern, see `glob()`.
+    pub async fn glob_async(
+        &self,
+        url: &str,
+        cloud_options: Option<&CloudOptions>,
+    ) -> Result<Vec<String>, ObstacleError> {
+        glob::list(self, url, cloud_options).await
+    }
+
+    /// List the objects saved in the cache, see `cache::list()`.
+    #[cfg(feature = "async")]
+    pub fn list(&self) -> Result<Vec<CacheEntry>, ObstacleError> {
+        cache::list_entries(&self.cache_config())
+    }
+
+    /// Report the space used by the cache, see `cache::usage()`.
+    #[cfg(feature = "async")]
+    pub fn usage(&self) -> Result<CacheUsage, ObstacleError> {
+        cache::usage_of(&self.cache_config())
+    }
+
+    /// Remove all the cached versions of the url, see `cache::evict()`.
+    #[cfg(feature = "async")]
+    pub fn evict(&self, url: &str) -> Result<usize, ObstacleError> {
+        cache::evict_matching(&self.cache_config(), |entry| entry.url == url)
+    }
+
+    /// Remove the cached entries for all the urls starting with the prefix, see `cache::evict_prefix()`.
+    #[cfg(feature = "async")]
+    pub fn evict_prefix(&self, prefix: &str) -> Result<usize, ObstacleError> {
+        cache::evict_matching(&self.cache_config(), |entry| entry.url.starts_with(prefix))
+    }
+
+    /// Evict the least recently used entries until the cache fits in the budget, see `cache::prune()`.
+    #[cfg(feature = "async")]
+    pub fn prune(
+        &self,
+        max_size: Option<u64>,
+        max_files: Option<usize>,
+    ) -> Result<usize, ObstacleError> {
+        cache::prune_to(&self.cache_config(), max_size, max_files)
+    }
+
+    /// Remove all the entries from the cache, see `cache::clear()`.
+    #[cfg(feature = "async")]
+    pub fn clear(&self) -> Result<usize, ObstacleError> {
+        cache::evict_matching(&self.cache_config(), |_| true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_store_pool() {
+        let obstacle = Obstacle::new();
+        let (_, first) = obstacle.store("file:///tmp/a.csv", None).unwrap();
+        let (_, second) = obstacle.store("file:///var/b.csv", None).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        // Separate instances do not share their stores.
+        let (_, other) = Obstacle::new().store("file:///tmp/a.csv", None).unwrap();
+        assert!(!Arc::ptr_eq(&first, &other));
+        // Updating the options drops the stores.
+        obstacle.set_cloud_options(CloudOptions::default());
+        let (_, third) = obstacle.store("file:///tmp/a.csv", None).unwrap();
+        assert!(!Arc::ptr_eq(&first, &third));
+    }
+
+    #[cfg(feature = "aws")]
+    #[test]
+    fn test_store_options() {
+        use crate::cloud::AmazonS3ConfigKey;
+
+        let region =
+            |region: &str| CloudOptions::default().with_aws([(AmazonS3ConfigKey::Region, region)]);
+        let obstacle = Obstacle::new().with_cloud_options_resolver(
+            CloudOptionsResolver::default()
+                .with_options("s3://one", region("us-east-1"))
+                .with_options("s3://two", region("eu-west-1")),
+        );
+        assert_eq!(
+            obstacle.resolve_cloud_options("s3://one/a.csv"),
+            Some(region("us-east-1"))
+        );
+        assert_eq!(
+            Obstacle::new().resolve_cloud_options("s3://one/a.csv"),
+            None
+        );
+        let (_, first) = obstacle.store("s3://one/a.csv", None).unwrap();
+        let (_, second) = obstacle
+            .store("s3://one/b/c.csv", Some(&region("us-east-1")))
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        let (_, third) = obstacle
+            .store("s3://one/a.csv", Some(&region("eu-west-1")))
+            .unwrap();
+        assert!(!Arc::ptr_eq(&first, &third));
+        let (_, fourth) = obstacle.store("s3://two/a.csv", None).unwrap();
+        assert!(!Arc::ptr_eq(&first, &fourth));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_cache_per_instance() {
+        let dir = std::env::temp_dir().join(format!("obstacle_context_{}", std::process::id()));
+        let source = dir.join("source.csv");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&source, "a,b\n1,2\n").unwrap();
+        let url = format!("file://{}", source.display());
+        let config = |name: &str| {
+            CacheConfig::default()
+                .with_root(dir.join(name))
+                .with_local_copy(true)
+        };
+        let one = Obstacle::new().with_cache_config(config("one"));
+        let two = Obstacle::new().with_cache_config(config("two"));
+
+        assert_eq!(&one.mmap(&url).unwrap().unwrap()[..], b"a,b\n1,2\n");
+        assert_eq!(one.usage().unwrap().entries, 1);
+        assert_eq!(two.usage().unwrap().entries, 0);
+        assert_eq!(one.list().unwrap()[0].url, url);
+        assert_eq!(one.clear().unwrap(), 1);
+        assert_eq!(one.usage().unwrap().entries, 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
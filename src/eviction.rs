@@ -0,0 +1,318 @@
+//! Keep the cache within the budget set in `CacheConfig`, the least recently used files are evicted first.
+//!
+//! The last access of a cached file is tracked through its modification time, which is updated on every
+//! cache hit. The access time maintained by the file system is not reliable, most systems use `noatime`
+//! or `relatime` mounts.
+//!
+//! Scanning the cache is expensive, the usage of each cache root is estimated from the files added since its
+//! last scan and the cache is only scanned again once the estimate exceeds the budget. Files added by other
+//! processes are accounted for at the next scan.
+//!
+//! The directories of the objects in use in this process, the sparse files being fetched, are never evicted,
+//! see `InUse`. The objects locked by another process are skipped as well.
+
+use crate::cache_config::CacheConfig;
+use crate::err::ObstacleError;
+use crate::lock::CacheLock;
+use log::debug;
+use std::collections::BTreeMap;
+use std::fs::{self, File, Metadata};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// The size and number of files of each cache root, estimated since the last scan.
+static ESTIMATES: Mutex<BTreeMap<PathBuf, (u64, usize)>> = Mutex::new(BTreeMap::new());
+
+/// The number of `InUse` guards for each object directory.
+static IN_USE: Mutex<BTreeMap<PathBuf, usize>> = Mutex::new(BTreeMap::new());
+
+/// Protects the files of an object directory from eviction until dropped.
+pub struct InUse {
+    dir: PathBuf,
+}
+
+impl InUse {
+    pub fn new(dir: &Path) -> Self {
+        *IN_USE.lock().unwrap().entry(dir.to_path_buf()).or_default() += 1;
+        InUse {
+            dir: dir.to_path_buf(),
+        }
+    }
+}
+
+impl Drop for InUse {
+    fn drop(&mut self) {
+        let mut in_use = IN_USE.lock().unwrap();
+        if let Some(count) = in_use.get_mut(&self.dir) {
+            *count -= 1;
+            if *count == 0 {
+                in_use.remove(&self.dir);
+            }
+        }
+    }
+}
+
+fn _is_in_use(path: &Path) -> bool {
+    path.parent()
+        .is_some_and(|dir| IN_USE.lock().unwrap().contains_key(dir))
+}
+
+/// A content or sparse file saved in the cache.
+#[derive(Debug)]
+pub struct CachedFile {
+    pub path: PathBuf,
+    /// The space used on disk, for sparse files this only includes the fetched blocks.
+    pub size: u64,
+    pub accessed: SystemTime,
+}
+
+/// Record an access to a cached file.
+pub fn touch(path: &Path) {
+    let result = File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(err) = result {
+        debug!(
+            "cannot update the access time of {}: {}",
+            path.display(),
+            err
+        );
+    }
+}
+
+#[cfg(unix)]
+fn _disk_usage(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn _disk_usage(metadata: &Metadata) -> u64 {
+    metadata.len()
+}
+
+fn _is_cached_file(file_name: &str) -> bool {
+    file_name.starts_with("content_") || file_name.starts_with("sparse_")
+}
+
+/// List all the content and sparse files under the cache root.
+pub fn scan(root: &Path) -> Result<Vec<CachedFile>, ObstacleError> {
+    let mut files = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+                continue;
+            }
+            if !_is_cached_file(&entry.file_name().to_string_lossy()) {
+                continue;
+            }
+            files.push(CachedFile {
+                path: entry.path(),
+                size: _disk_usage(&metadata),
+                accessed: metadata.modified()?,
+            });
+        }
+    }
+    Ok(files)
+}
+
+/// Remove a cached file with its sidecars, the metadata and, for sparse files, the block bitmap.
+/// Files in use or locked by another process are skipped, return true when the file was removed.
+pub fn remove(path: &Path) -> Result<bool, ObstacleError> {
+    if _is_in_use(path) {
+        debug!("skipping in use {}", path.display());
+        return Ok(false);
+    }
+    let _lock = match path.parent().map(CacheLock::try_exclusive).transpose()? {
+        Some(None) => {
+            debug!("skipping locked {}", path.display());
+            return Ok(false);
+        }
+        lock => lock,
+    };
+    debug!("evicting {}", path.display());
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let sidecars = match (
+        file_name.strip_prefix("sparse_"),
+        file_name.strip_prefix("content_"),
+    ) {
+        (Some(key), _) => vec![format!("blocks_{}", key), format!("meta_{}", key)],
+        (_, Some(key)) => vec![format!("meta_{}", key)],
+        _ => vec![],
+    };
+    for sidecar in sidecars {
+        match fs::remove_file(path.with_file_name(sidecar)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    Ok(true)
+}
+
+/// Select the least recently used files to evict in order to fit in the budget.
+/// `keep` and the files in use are never selected.
+fn _select_evictions<'a>(
+    files: &'a mut [CachedFile],
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+    keep: &Path,
+) -> Vec<&'a CachedFile> {
+    files.sort_by_key(|file| file.accessed);
+    let mut size: u64 = files.iter().map(|file| file.size).sum();
+    let mut count = files.len();
+    let mut evicted = Vec::new();
+    for file in files.iter() {
+        let over_size = max_size.is_some_and(|max_size| size > max_size);
+        let over_count = max_files.is_some_and(|max_files| count > max_files);
+        if !over_size && !over_count {
+            break;
+        }
+        if file.path == keep || _is_in_use(&file.path) {
+            continue;
+        }
+        size -= file.size;
+        count -= 1;
+        evicted.push(file);
+    }
+    evicted
+}
+
+/// Evict the least recently used files under `root` until they fit in the budget, return the number of files evicted.
+pub fn evict_to_fit(
+    root: &Path,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+    keep: &Path,
+) -> Result<usize, ObstacleError> {
+    let mut files = scan(root)?;
+    let mut size: u64 = files.iter().map(|file| file.size).sum();
+    let mut count = files.len();
+    let mut evicted = 0;
+    for file in _select_evictions(&mut files, max_size, max_files, keep) {
+        if remove(&file.path)? {
+            size -= file.size;
+            count -= 1;
+            evicted += 1;
+        }
+    }
+    ESTIMATES
+        .lock()
+        .unwrap()
+        .insert(root.to_path_buf(), (size, count));
+    Ok(evicted)
+}
+
+/// Evict the least recently used files until the cache fits in the configured budget.
+///
+/// `added_size` bytes and `added_files` files were just added to the cache, the cache is only scanned when the
+/// estimated usage exceeds the budget.
+pub fn enforce_budget(
+    config: &CacheConfig,
+    keep: &Path,
+    added_size: u64,
+    added_files: usize,
+) -> Result<(), ObstacleError> {
+    if config.max_size().is_none() && config.max_files().is_none() {
+        return Ok(());
+    }
+    let root = config.root()?;
+    if let Some((size, files)) = ESTIMATES.lock().unwrap().get_mut(&root) {
+        *size += added_size;
+        *files += added_files;
+        let over_size = config.max_size().is_some_and(|max_size| *size > max_size);
+        let over_count = config
+            .max_files()
+            .is_some_and(|max_files| *files > max_files);
+        if !over_size && !over_count {
+            return Ok(());
+        }
+    }
+    evict_to_fit(&root, config.max_size(), config.max_files(), keep)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn cached_file(name: &str, size: u64, accessed: u64) -> CachedFile {
+        CachedFile {
+            path: PathBuf::from(name),
+            size,
+            accessed: SystemTime::UNIX_EPOCH + Duration::from_secs(accessed),
+        }
+    }
+
+    fn evicted_names(
+        mut files: Vec<CachedFile>,
+        max_size: Option<u64>,
+        max_files: Option<usize>,
+        keep: &str,
+    ) -> Vec<String> {
+        _select_evictions(&mut files, max_size, max_files, Path::new(keep))
+            .iter()
+            .map(|file| file.path.display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_select_evictions() {
+        let files = || {
+            vec![
+                cached_file("c", 10, 3),
+                cached_file("a", 10, 1),
+                cached_file("b", 10, 2),
+            ]
+        };
+        assert!(evicted_names(files(), None, None, "").is_empty());
+        assert!(evicted_names(files(), Some(30), Some(3), "").is_empty());
+        // The least recently used files are evicted first.
+        assert_eq!(evicted_names(files(), Some(15), None, ""), vec!["a", "b"]);
+        assert_eq!(evicted_names(files(), None, Some(2), ""), vec!["a"]);
+        // The file being returned to the caller is kept.
+        assert_eq!(evicted_names(files(), Some(20), None, "a"), vec!["b"]);
+    }
+
+    #[test]
+    fn test_in_use() {
+        let dir = std::env::temp_dir().join(format!("obstacle_in_use_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sparse_1234");
+        fs::write(&path, "content").unwrap();
+        let in_use = InUse::new(&dir);
+        let other = InUse::new(&dir);
+        assert!(!remove(&path).unwrap());
+        let files = vec![CachedFile {
+            path: path.clone(),
+            size: 10,
+            accessed: SystemTime::UNIX_EPOCH,
+        }];
+        assert!(evicted_names(files, Some(0), None, "").is_empty());
+        drop(in_use);
+        assert!(!remove(&path).unwrap());
+        drop(other);
+        assert!(remove(&path).unwrap());
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
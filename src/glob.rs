@@ -4,7 +4,9 @@ use object_store::path::Path;
 use regex::Regex;
 use url::Url;
 
+use crate::context::Obstacle;
 use crate::err::{obstinate_err, ObstacleError};
+use crate::runtime::block_on;
 use crate::CloudOptions;
 
 const DELIMITER: char = '/';
@@ -94,12 +96,16 @@ impl CloudLocation {
             ("".into(), url[7..].into())
         } else {
             let key = parsed.path();
-            let bucket = parsed
+            let mut bucket = parsed
                 .host()
                 .ok_or_else(|| {
                     ObstacleError::new(format!("cannot parse bucket (host) from url: {}", url))
                 })?
                 .to_string();
+            // Keep the port of http servers.
+            if let Some(port) = parsed.port() {
+                bucket = format!("{}:{}", bucket, port);
+            }
             (bucket, key)
         };
         let (mut prefix, expansion) = extract_prefix_expansion(key)?;
@@ -133,9 +139,12 @@ struct Matcher {
 
 impl Matcher {
     /// Build a Matcher for the given prefix and expansion.
+    ///
+    /// The listed locations are relative paths, a leading delimiter of the prefix is ignored.
     fn new(prefix: String, expansion: Option<&str>) -> Result<Matcher, ObstacleError> {
         // Cloud APIs accept a prefix without any expansion, extract it.
         let re = expansion.map(Regex::new).transpose()?;
+        let prefix = prefix.trim_start_matches(DELIMITER).to_string();
         Ok(Matcher { prefix, re })
     }
 
@@ -153,9 +162,24 @@ impl Matcher {
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
 /// List files with a prefix derived from the pattern.
-pub async fn glob(
+///
+/// Without options, the options set for the url with `set_cloud_options_resolver()` are used.
+pub fn glob(url: &str, cloud_options: Option<&CloudOptions>) -> Result<Vec<String>, ObstacleError> {
+    block_on(glob_async(url, cloud_options))
+}
+
+/// List files with a prefix derived from the pattern, see `glob()`.
+pub async fn glob_async(
+    url: &str,
+    cloud_options: Option<&CloudOptions>,
+) -> Result<Vec<String>, ObstacleError> {
+    list(Obstacle::global(), url, cloud_options).await
+}
+
+/// List files with the stores of the instance.
+pub(crate) async fn list(
+    obstacle: &Obstacle,
     url: &str,
     cloud_options: Option<&CloudOptions>,
 ) -> Result<Vec<String>, ObstacleError> {
@@ -169,7 +193,7 @@ pub async fn glob(
             expansion,
         },
         store,
-    ) = super::build(url, cloud_options)?;
+    ) = obstacle.store(url, cloud_options)?;
     let matcher = Matcher::new(prefix.clone(), expansion.as_deref())?;
 
     let list_stream = store.list(Some(&Path::from(prefix))).await?;
@@ -220,6 +244,15 @@ mod test {
                 expansion: None,
             }
         );
+        assert_eq!(
+            CloudLocation::new("http://localhost:8080/a/*.csv").unwrap(),
+            CloudLocation {
+                scheme: "http".into(),
+                bucket: "localhost:8080".into(),
+                prefix: "a/".into(),
+                expansion: Some("^([^/]*)\\.csv$".into()),
+            }
+        );
     }
 
     #[test]
@@ -280,4 +313,23 @@ mod test {
         // Required folder is present and additional folders are allowed.
         assert!(a.is_matching(&Path::from("folder/other/data/1.parquet")));
     }
+
+    #[test]
+    fn test_matcher_leading_delimiter() {
+        // The prefixes of absolute local paths start with the delimiter, `Path` never does.
+        let a = Matcher::new("/folder/".into(), Some("^([^/]*)\\.parquet$")).unwrap();
+        assert!(a.is_matching(&Path::from("folder/1.parquet")));
+        assert!(!a.is_matching(&Path::from("other/1.parquet")));
+        let b = Matcher::new("/folder/1.parquet".into(), None).unwrap();
+        assert!(b.is_matching(&Path::from("/folder/1.parquet")));
+    }
+
+    #[test]
+    fn test_matcher_local() {
+        let cloud_location = CloudLocation::new("file:///tmp/folder/*.parquet").unwrap();
+        let a = Matcher::new(cloud_location.prefix, cloud_location.expansion.as_deref()).unwrap();
+        // The local file system lists the locations without the leading delimiter.
+        assert!(a.is_matching(&Path::from("tmp/folder/1.parquet")));
+        assert!(!a.is_matching(&Path::from("tmp/folder/other/1.parquet")));
+    }
 }
@@ -0,0 +1,144 @@
+//! Verify that the cached content is complete and uncorrupted.
+//!
+//! The size of the content is checked on every cache hit. When a checksum is configured with
+//! `CacheConfig::with_checksum()` the digest of the content is computed after each download, compared with
+//! the digest supplied by the provider when available, recorded in the metadata of the content and
+//! verified on every cache hit. Corrupted content is removed and downloaded again.
+
+use crate::err::ObstacleError;
+use md5::Md5;
+use object_store::ObjectMeta;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read};
+
+/// The checksums available to verify the cached content.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Crc32c,
+    /// Also compared with the e-tag of objects uploaded in a single part to S3 or GCS, which is the MD5 of
+    /// the content. Do not use with objects encrypted with SSE-KMS, their e-tag is not a digest of the content.
+    Md5,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    fn name(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Crc32c => "crc32c",
+            ChecksumAlgorithm::Md5 => "md5",
+            ChecksumAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// The digest of the content as `<algorithm>:<hex>`.
+    pub fn digest(&self, file: &mut File) -> Result<String, ObstacleError> {
+        let hex = match self {
+            ChecksumAlgorithm::Crc32c => {
+                let mut crc = 0;
+                let mut buffer = vec![0u8; 1024 * 1024];
+                loop {
+                    let read = file.read(&mut buffer)?;
+                    if read == 0 {
+                        break;
+                    }
+                    crc = crc32c::crc32c_append(crc, &buffer[..read]);
+                }
+                format!("{:08x}", crc)
+            }
+            ChecksumAlgorithm::Md5 => {
+                let mut hasher = Md5::new();
+                io::copy(file, &mut hasher)?;
+                format!("{:x}", hasher.finalize())
+            }
+            ChecksumAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                io::copy(file, &mut hasher)?;
+                format!("{:x}", hasher.finalize())
+            }
+        };
+        Ok(format!("{}:{}", self.name(), hex))
+    }
+
+    /// The digest supplied by the provider in the metadata of the object, if any.
+    pub(crate) fn provider_digest(&self, meta: &ObjectMeta) -> Option<String> {
+        match self {
+            ChecksumAlgorithm::Md5 => {
+                let e_tag = meta.e_tag.as_ref()?.trim_matches('"').to_lowercase();
+                let is_md5 = e_tag.len() == 32 && e_tag.chars().all(|c| c.is_ascii_hexdigit());
+                is_md5.then(|| format!("{}:{}", self.name(), e_tag))
+            }
+            _ => None,
+        }
+    }
+
+    /// True when the digest was computed with this algorithm.
+    pub(crate) fn computed(&self, digest: &str) -> bool {
+        digest
+            .split_once(':')
+            .is_some_and(|(name, _)| name == self.name())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+    use object_store::path::Path;
+    use std::io::Write;
+
+    fn digest(algorithm: ChecksumAlgorithm, content: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "obstacle_digest_{}_{}",
+            algorithm.name(),
+            std::process::id()
+        ));
+        File::create(&path).unwrap().write_all(content).unwrap();
+        let digest = algorithm.digest(&mut File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        digest
+    }
+
+    #[test]
+    fn test_digest() {
+        assert_eq!(
+            digest(ChecksumAlgorithm::Crc32c, b"123456789"),
+            "crc32c:e3069283"
+        );
+        assert_eq!(
+            digest(ChecksumAlgorithm::Md5, b""),
+            "md5:d41d8cd98f00b204e9800998ecf8427e"
+        );
+        assert_eq!(
+            digest(ChecksumAlgorithm::Sha256, b"hello world"),
+            "sha256:b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+        assert!(ChecksumAlgorithm::Md5.computed("md5:d41d8cd98f00b204e9800998ecf8427e"));
+        assert!(!ChecksumAlgorithm::Sha256.computed("md5:d41d8cd98f00b204e9800998ecf8427e"));
+    }
+
+    #[test]
+    fn test_provider_digest() {
+        let meta = |e_tag: &str| ObjectMeta {
+            location: Path::from("a"),
+            last_modified: Utc::now(),
+            size: 0,
+            e_tag: Some(e_tag.into()),
+            version: None,
+        };
+        assert_eq!(
+            ChecksumAlgorithm::Md5.provider_digest(&meta("\"D41D8CD98F00B204E9800998ECF8427E\"")),
+            Some("md5:d41d8cd98f00b204e9800998ecf8427e".into())
+        );
+        // Multipart uploads have the number of parts appended to the e-tag.
+        assert_eq!(
+            ChecksumAlgorithm::Md5.provider_digest(&meta("\"d41d8cd98f00b204e9800998ecf8427e-3\"")),
+            None
+        );
+        assert_eq!(
+            ChecksumAlgorithm::Sha256
+                .provider_digest(&meta("\"d41d8cd98f00b204e9800998ecf8427e\"")),
+            None
+        );
+    }
+}
@@ -0,0 +1,403 @@
+//! Lazy memory maps, the pages are downloaded on first access.
+//!
+//! The content is exposed through an anonymous mapping registered with
+//! [userfaultfd](https://man7.org/linux/man-pages/man2/userfaultfd.2.html). A handler thread receives the
+//! page faults, fetches the corresponding block in the sparse file and copies it into the mapping.
+//! This allows consumers that only know about `&[u8]` to read remote objects without downloading them fully.
+//!
+//! Unprivileged processes may only handle the faults of user space accesses, see `vm.unprivileged_userfaultfd`.
+//! Passing a lazy mapping to a system call, `write()` for example, then fails with `EFAULT`. When userfaultfd is
+//! not available at all, `Mmap::from_url()` downloads the object instead.
+//!
+//! The fetch of a block is retried with an increasing delay. A block that still cannot be fetched is made
+//! inaccessible, the faulting thread receives `SIGSEGV` instead of reading content that is not the object's.
+//! The error is logged and returned by the next call to `Mmap::advise()`.
+
+use crate::err::{obstinate_err, ObstacleError};
+use crate::runtime::block_on;
+use crate::sparse::{SparseFile, BLOCK_SIZE};
+use log::{debug, error, warn};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+static LAZY_MMAP: AtomicBool = AtomicBool::new(false);
+
+/// The number of attempts at fetching a block before making it inaccessible, a page fault cannot fail otherwise.
+const FETCH_ATTEMPTS: u32 = 6;
+
+/// The delay before the second attempt, doubled for each of the next ones.
+const FETCH_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Enable or disable lazy memory maps for `Mmap::from_url()`.
+pub fn set_lazy_mmap(enabled: bool) {
+    LAZY_MMAP.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_lazy_mmap() -> bool {
+    LAZY_MMAP.load(Ordering::Relaxed)
+}
+
+// Definitions from linux/userfaultfd.h, they are not exposed by the libc crate.
+const UFFD_API: u64 = 0xAA;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+const UFFD_USER_MODE_ONLY: libc::c_int = 1;
+
+/// Encode an ioctl request for userfaultfd, `dir` is 1 for write, 2 for read and 3 for both.
+const fn uffd_ioctl(dir: u64, nr: u64, size: usize) -> u64 {
+    (dir << 30) | ((size as u64) << 16) | (UFFD_API << 8) | nr
+}
+
+const UFFDIO_API: u64 = uffd_ioctl(3, 0x3F, std::mem::size_of::<UffdioApi>());
+const UFFDIO_REGISTER: u64 = uffd_ioctl(3, 0x00, std::mem::size_of::<UffdioRegister>());
+const UFFDIO_WAKE: u64 = uffd_ioctl(2, 0x02, std::mem::size_of::<UffdioRange>());
+const UFFDIO_COPY: u64 = uffd_ioctl(3, 0x03, std::mem::size_of::<UffdioCopy>());
+
+#[repr(C)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+struct UffdioRegister {
+    start: u64,
+    len: u64,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdMsg {
+    event: u8,
+    reserved1: u8,
+    reserved2: u16,
+    reserved3: u32,
+    flags: u64,
+    address: u64,
+    ptid: u32,
+    padding: u32,
+}
+
+/// A file descriptor closed on drop.
+struct Fd(libc::c_int);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+fn check(result: libc::c_long) -> Result<libc::c_long, io::Error> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// The flags accepted by userfaultfd in this process, checked once.
+static USERFAULTFD_FLAGS: OnceLock<Option<libc::c_int>> = OnceLock::new();
+
+/// The flags of the userfaultfd, limited to the faults of user space accesses when the process is not allowed
+/// more. None when userfaultfd is not available to this process.
+fn _userfaultfd_flags() -> Option<libc::c_int> {
+    *USERFAULTFD_FLAGS.get_or_init(|| {
+        let flags = libc::O_CLOEXEC | libc::O_NONBLOCK;
+        let err = match check(unsafe { libc::syscall(libc::SYS_userfaultfd, flags) }) {
+            Ok(fd) => {
+                drop(Fd(fd as libc::c_int));
+                return Some(flags);
+            }
+            Err(err) => err,
+        };
+        if err.raw_os_error() != Some(libc::EPERM) {
+            warn!("userfaultfd is not available: {}", err);
+            return None;
+        }
+        debug!("userfaultfd is not allowed, retrying for user space faults only");
+        let flags = flags | UFFD_USER_MODE_ONLY;
+        match check(unsafe { libc::syscall(libc::SYS_userfaultfd, flags) }) {
+            Ok(fd) => {
+                drop(Fd(fd as libc::c_int));
+                Some(flags)
+            }
+            Err(err) => {
+                warn!("userfaultfd is not available: {}", err);
+                None
+            }
+        }
+    })
+}
+
+/// True when lazy memory maps can be created, `Mmap::from_url()` downloads the objects otherwise.
+pub fn is_userfaultfd_available() -> bool {
+    _userfaultfd_flags().is_some()
+}
+
+/// Serves the page faults of a lazy mapping, stops the handler thread on drop.
+pub struct LazyMapping {
+    /// Signals the handler thread to stop.
+    stop: Arc<Fd>,
+    handler: Option<JoinHandle<()>>,
+    /// The first error of the handler thread, not reported yet.
+    error: Arc<Mutex<Option<ObstacleError>>>,
+}
+
+impl LazyMapping {
+    /// Register the mapping starting at `base` with userfaultfd and start serving its page faults.
+    ///
+    /// The mapping must be anonymous, page aligned and at least as large as the sparse file.
+    /// Fails when userfaultfd is not available, see `is_userfaultfd_available()`.
+    pub fn new(
+        base: *const u8,
+        len: usize,
+        sparse: Arc<SparseFile>,
+    ) -> Result<Self, ObstacleError> {
+        // The kernel maps whole pages, the registered range must cover all of them.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let len = len.div_ceil(page_size) * page_size;
+        let flags = match _userfaultfd_flags() {
+            Some(flags) => flags,
+            None => return obstinate_err("userfaultfd is not available"),
+        };
+        let uffd =
+            Fd(check(unsafe { libc::syscall(libc::SYS_userfaultfd, flags) })? as libc::c_int);
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            features: 0,
+            ioctls: 0,
+        };
+        check(unsafe { libc::ioctl(uffd.0, UFFDIO_API as _, &mut api) } as libc::c_long)?;
+        let mut register = UffdioRegister {
+            start: base as u64,
+            len: len as u64,
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        check(unsafe { libc::ioctl(uffd.0, UFFDIO_REGISTER as _, &mut register) } as libc::c_long)?;
+        let stop = Arc::new(Fd(
+            check(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) } as libc::c_long)? as libc::c_int,
+        ));
+
+        let handler_stop = stop.clone();
+        let error = Arc::new(Mutex::new(None));
+        let handler_error = error.clone();
+        let base = base as usize;
+        let handler = std::thread::Builder::new()
+            .name("obstacle-lazy".into())
+            .spawn(move || {
+                if let Err(err) =
+                    _serve_faults(&uffd, &handler_stop, base, len, &sparse, &handler_error)
+                {
+                    error!("lazy mapping failed: {}", err);
+                    _record_error(&handler_error, err);
+                    // Closing the userfaultfd wakes the faulting threads, the missing pages would read as zeros.
+                    if let Err(err) = _revoke(&uffd, base, len) {
+                        error!("cannot revoke the lazy mapping: {}", err);
+                    }
+                }
+            })?;
+        Ok(LazyMapping {
+            stop,
+            handler: Some(handler),
+            error,
+        })
+    }
+
+    /// The first error of the handler since the last call, the pages it concerns are inaccessible.
+    pub fn take_error(&self) -> Option<ObstacleError> {
+        self.error.lock().unwrap().take()
+    }
+}
+
+impl Drop for LazyMapping {
+    fn drop(&mut self) {
+        let one: u64 = 1;
+        unsafe { libc::write(self.stop.0, &one as *const u64 as *const libc::c_void, 8) };
+        if let Some(handler) = self.handler.take() {
+            let _ = handler.join();
+        }
+        if let Some(err) = self.take_error() {
+            error!("lazy mapping was missing some pages: {}", err);
+        }
+    }
+}
+
+/// Keep the first error until it is reported.
+fn _record_error(error: &Mutex<Option<ObstacleError>>, err: ObstacleError) {
+    error.lock().unwrap().get_or_insert(err);
+}
+
+/// Replace the pages of the range with inaccessible ones and wake the threads waiting on them,
+/// their accesses fail with `SIGSEGV`.
+fn _revoke(uffd: &Fd, start: usize, len: usize) -> Result<(), ObstacleError> {
+    let flags = libc::MAP_FIXED | libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+    let addr = unsafe {
+        libc::mmap(
+            start as *mut libc::c_void,
+            len,
+            libc::PROT_NONE,
+            flags,
+            -1,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mut range = UffdioRange {
+        start: start as u64,
+        len: len as u64,
+    };
+    check(unsafe { libc::ioctl(uffd.0, UFFDIO_WAKE as _, &mut range) } as libc::c_long)?;
+    Ok(())
+}
+
+/// Wait for page faults and resolve them by copying the containing block from the sparse file.
+fn _serve_faults(
+    uffd: &Fd,
+    stop: &Fd,
+    base: usize,
+    len: usize,
+    sparse: &SparseFile,
+    error: &Mutex<Option<ObstacleError>>,
+) -> Result<(), ObstacleError> {
+    let mut buffer = vec![0u8; BLOCK_SIZE];
+    loop {
+        let mut fds = [
+            libc::pollfd {
+                fd: uffd.0,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: stop.0,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if let Err(err) = check(unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } as libc::c_long) {
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err.into());
+        }
+        if fds[1].revents != 0 {
+            debug!("stopping lazy mapping handler");
+            return Ok(());
+        }
+
+        let mut msg = UffdMsg::default();
+        let size = std::mem::size_of::<UffdMsg>();
+        match check(unsafe {
+            libc::read(uffd.0, &mut msg as *mut UffdMsg as *mut libc::c_void, size)
+        } as libc::c_long)
+        {
+            Ok(read) if read as usize == size => {}
+            Ok(read) => return obstinate_err(format!("short userfaultfd read of {} bytes", read)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Err(err.into()),
+        }
+        if msg.event != UFFD_EVENT_PAGEFAULT {
+            continue;
+        }
+
+        // Resolve the whole block containing the faulting address.
+        let start = (msg.address as usize - base) / BLOCK_SIZE * BLOCK_SIZE;
+        let end = (start + BLOCK_SIZE).min(len);
+        debug!(
+            "page fault at offset {}, fetching {}..{}",
+            msg.address as usize - base,
+            start,
+            end
+        );
+        let mut attempt = 0;
+        let fetched = loop {
+            attempt += 1;
+            match block_on(sparse.fetch(start..end)) {
+                Ok(()) => break Ok(()),
+                Err(err) if attempt >= FETCH_ATTEMPTS => break Err(err),
+                Err(err) => {
+                    debug!(
+                        "attempt {} at fetching {}..{} failed: {}",
+                        attempt, start, end, err
+                    );
+                    std::thread::sleep(FETCH_RETRY_DELAY * 2u32.pow(attempt - 1));
+                }
+            }
+        };
+        if let Err(err) = fetched {
+            error!(
+                "cannot fetch {}..{} of the lazy mapping, its accesses will fail: {}",
+                start, end, err
+            );
+            _record_error(error, err);
+            _revoke(uffd, base + start, end - start)?;
+            continue;
+        }
+        let block = &mut buffer[..end - start];
+        block.fill(0);
+        let available = sparse.len().saturating_sub(start).min(block.len());
+        sparse
+            .file()
+            .read_exact_at(&mut block[..available], start as u64)?;
+
+        let mut copy = UffdioCopy {
+            dst: (base + start) as u64,
+            src: block.as_ptr() as u64,
+            len: block.len() as u64,
+            mode: 0,
+            copy: 0,
+        };
+        let range = UffdioRange {
+            start: copy.dst,
+            len: copy.len,
+        };
+        _resolve(uffd, UFFDIO_COPY, &mut copy, &range)?;
+    }
+}
+
+/// Resolve a fault with the `UFFDIO_COPY` request.
+fn _resolve<T>(
+    uffd: &Fd,
+    request: u64,
+    arg: &mut T,
+    range: &UffdioRange,
+) -> Result<(), ObstacleError> {
+    if let Err(err) =
+        check(unsafe { libc::ioctl(uffd.0, request as _, arg as *mut T) } as libc::c_long)
+    {
+        if err.raw_os_error() != Some(libc::EEXIST) {
+            return Err(err.into());
+        }
+        // The block was resolved by an earlier fault, make sure the faulting thread is not left waiting.
+        let mut range = UffdioRange {
+            start: range.start,
+            len: range.len,
+        };
+        check(unsafe { libc::ioctl(uffd.0, UFFDIO_WAKE as _, &mut range) } as libc::c_long)?;
+    }
+    Ok(())
+}
@@ -1,11 +1,50 @@
+#[cfg(feature = "aws")]
+mod aws_profile;
 #[cfg(feature = "async")]
-mod cache;
+pub mod cache;
+#[cfg(feature = "async")]
+mod cache_config;
+#[cfg(feature = "async")]
+mod cache_key;
+#[cfg(feature = "async")]
+mod cache_path;
 mod cloud;
+mod context;
 mod err;
+#[cfg(feature = "async")]
+mod eviction;
 mod glob;
+#[cfg(feature = "async")]
+mod integrity;
+#[cfg(all(feature = "lazy", target_os = "linux"))]
+mod lazy;
+#[cfg(feature = "async")]
+mod lock;
+#[cfg(feature = "async")]
+mod memory;
+#[cfg(feature = "async")]
+mod metadata;
 mod mmap;
+mod runtime;
+#[cfg(all(feature = "async", unix))]
+mod sparse;
 
+#[cfg(feature = "async")]
+pub use cache_config::{set_cache_config, CacheConfig, CachePolicy, CACHE_DIR_ENV};
+#[cfg(feature = "async")]
+pub use cache_key::{
+    CacheKeyStrategy, CachedVersion, ContentHashKey, ETagKey, LastModifiedKey, VersionKey,
+};
 pub use cloud::*;
+pub use context::Obstacle;
 pub use err::ObstacleError;
+pub use glob::{glob, glob_async};
+#[cfg(feature = "async")]
+pub use integrity::ChecksumAlgorithm;
+#[cfg(all(feature = "lazy", target_os = "linux"))]
+pub use lazy::set_lazy_mmap;
+#[cfg(feature = "async")]
+pub use memory::{delete_memory_object, memory_store, put_memory_object, put_memory_object_async};
 pub use mmap::*;
 pub use object_store::ClientConfigKey;
+pub use runtime::set_runtime_handle;
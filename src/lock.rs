@@ -0,0 +1,57 @@
+//! Advisory locks coordinating the processes sharing the cache.
+//!
+//! Each cached object has a `lock` file in its cache directory. The lock is held while the content files
+//! of the object are created, renamed or removed, so only one process downloads a given version and
+//! the other processes wait and reuse the result.
+
+use crate::err::ObstacleError;
+use fs4::FileExt;
+use log::debug;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::Path;
+use tokio::task::spawn_blocking;
+
+/// The name of the lock file in the cache directory of an object.
+pub const LOCK_FILE: &str = "lock";
+
+/// An exclusive lock on the cache directory of an object, released on drop.
+pub struct CacheLock {
+    file: File,
+}
+
+fn _open_lock_file(dir: &Path) -> Result<File, ObstacleError> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(dir.join(LOCK_FILE))?)
+}
+
+impl CacheLock {
+    /// Wait for the exclusive lock on the cache directory.
+    pub async fn exclusive(dir: &Path) -> Result<CacheLock, ObstacleError> {
+        let file = _open_lock_file(dir)?;
+        debug!("locking {}", dir.display());
+        let file = spawn_blocking(move || file.lock_exclusive().map(|_| file))
+            .await
+            .map_err(ObstacleError::from_err)??;
+        Ok(CacheLock { file })
+    }
+
+    /// Take the exclusive lock on the cache directory if no other process holds it.
+    pub fn try_exclusive(dir: &Path) -> Result<Option<CacheLock>, ObstacleError> {
+        let file = _open_lock_file(dir)?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(Some(CacheLock { file })),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
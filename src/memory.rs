@@ -0,0 +1,61 @@
+//! Named in memory stores for `memory://<name>/<key>` urls.
+//!
+//! The stores live as long as the process, they are shared by all the `Obstacle` instances. They go through the
+//! cache like the cloud stores, which makes them useful to test applications without a cloud account.
+
+use crate::err::{obstinate_err, ObstacleError};
+use crate::glob::CloudLocation;
+use crate::runtime::block_on;
+use object_store::memory::InMemory;
+use object_store::path::Path as ObjectStorePath;
+use object_store::ObjectStore;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+static MEMORY_STORES: Mutex<BTreeMap<String, Arc<dyn ObjectStore>>> = Mutex::new(BTreeMap::new());
+
+/// The in memory store with the name, created empty on first use.
+pub fn memory_store(name: &str) -> Arc<dyn ObjectStore> {
+    MEMORY_STORES
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_insert_with(|| Arc::new(InMemory::new()))
+        .clone()
+}
+
+/// The name of the store and the path of the object for a `memory://` url.
+fn _memory_location(url: &str) -> Result<(String, ObjectStorePath), ObstacleError> {
+    let location = CloudLocation::new(url)?;
+    if location.scheme != "memory" || location.expansion.is_some() {
+        return obstinate_err(format!(
+            "expected a memory:// url without wildcards, got {}",
+            url
+        ));
+    }
+    let path = ObjectStorePath::from_url_path(&location.prefix)?;
+    Ok((location.bucket, path))
+}
+
+/// Save the content at the `memory://` url, replacing the previous content.
+pub fn put_memory_object(url: &str, content: impl Into<Vec<u8>>) -> Result<(), ObstacleError> {
+    block_on(put_memory_object_async(url, content.into()))
+}
+
+/// Save the content at the `memory://` url, see `put_memory_object()`.
+pub async fn put_memory_object_async(
+    url: &str,
+    content: impl Into<Vec<u8>>,
+) -> Result<(), ObstacleError> {
+    let (name, path) = _memory_location(url)?;
+    memory_store(&name)
+        .put(&path, content.into().into())
+        .await?;
+    Ok(())
+}
+
+/// Delete the object at the `memory://` url.
+pub fn delete_memory_object(url: &str) -> Result<(), ObstacleError> {
+    let (name, path) = _memory_location(url)?;
+    block_on(async { Ok(memory_store(&name).delete(&path).await?) })
+}
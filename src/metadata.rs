@@ -0,0 +1,122 @@
+//! Metadata saved alongside each cached object.
+//!
+//! The cache files are named after the cache key, the metadata keeps what the name cannot: the original url,
+//! the size and modification time of the object in the cloud, the content type, when the content was downloaded
+//! and last validated, and the digest of the content. It is saved as JSON in `meta_<key>` and replaced
+//! atomically on every update.
+
+use crate::cache_path::cache_file_name;
+use crate::err::ObstacleError;
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// The metadata of a cached object.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EntryMetadata {
+    /// The url of the object in the cloud.
+    pub url: String,
+    /// The size of the object in the cloud.
+    pub size: u64,
+    /// The modification time of the object in the cloud.
+    pub last_modified: DateTime<Utc>,
+    /// The media type of the object, if known.
+    pub content_type: Option<String>,
+    /// When the cached content was downloaded, or created for sparse files.
+    pub downloaded_at: DateTime<Utc>,
+    /// The last time the cached content was checked against the cloud.
+    pub validated_at: DateTime<Utc>,
+    /// The digest of the content, see `ChecksumAlgorithm::digest()`.
+    pub digest: Option<String>,
+}
+
+/// The media type of well known file extensions, object_store does not report the content type.
+pub(crate) fn guess_content_type(url: &str) -> Option<String> {
+    let file_name = url.rsplit('/').next()?;
+    let (_, extension) = file_name.rsplit_once('.')?;
+    let content_type = match extension.to_lowercase().as_str() {
+        "arrow" | "feather" | "ipc" => "application/vnd.apache.arrow.file",
+        "avro" => "application/avro",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "ndjson" | "jsonl" => "application/x-ndjson",
+        "parquet" => "application/vnd.apache.parquet",
+        "txt" => "text/plain",
+        _ => return None,
+    };
+    Some(content_type.to_string())
+}
+
+/// The metadata of the cached content with the given key is saved in `meta_<key>`.
+pub fn metadata_path(local_path: &Path, key: &str) -> PathBuf {
+    local_path.join(cache_file_name("meta", key))
+}
+
+/// Read the metadata of the cached content, None when missing or unreadable.
+pub fn read(local_path: &Path, key: &str) -> Option<EntryMetadata> {
+    let path = metadata_path(local_path, key);
+    let content = fs::read(&path).ok()?;
+    match serde_json::from_slice(&content) {
+        Ok(metadata) => Some(metadata),
+        Err(err) => {
+            debug!("ignoring invalid metadata {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Save the metadata of the cached content, readers see either the previous or the new metadata.
+pub fn write(local_path: &Path, key: &str, metadata: &EntryMetadata) -> Result<(), ObstacleError> {
+    let tempfile = local_path.join(format!("temp_{}", Uuid::new_v4()));
+    fs::write(
+        &tempfile,
+        serde_json::to_vec_pretty(metadata).map_err(ObstacleError::from_err)?,
+    )?;
+    if let Err(err) = fs::rename(&tempfile, metadata_path(local_path, key)) {
+        let _ = fs::remove_file(&tempfile);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_read() {
+        let dir = std::env::temp_dir().join(format!("obstacle_metadata_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let metadata = EntryMetadata {
+            url: "s3://bucket/a/b.parquet".into(),
+            size: 1234,
+            last_modified: Utc::now(),
+            content_type: guess_content_type("s3://bucket/a/b.parquet"),
+            downloaded_at: Utc::now(),
+            validated_at: Utc::now(),
+            digest: Some("crc32c:e3069283".into()),
+        };
+        assert_eq!(read(&dir, "1234"), None);
+        write(&dir, "1234", &metadata).unwrap();
+        assert_eq!(read(&dir, "1234"), Some(metadata));
+        fs::write(metadata_path(&dir, "1234"), "{").unwrap();
+        assert_eq!(read(&dir, "1234"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_guess_content_type() {
+        assert_eq!(
+            guess_content_type("s3://bucket/a/b.PARQUET"),
+            Some("application/vnd.apache.parquet".into())
+        );
+        assert_eq!(
+            guess_content_type("file:///tmp/a.csv"),
+            Some("text/csv".into())
+        );
+        assert_eq!(guess_content_type("s3://bucket.name/data"), None);
+    }
+}
@@ -1,24 +1,76 @@
 #[cfg(feature = "async")]
-use crate::cache::download_file;
-use crate::cloud::CloudType;
+use crate::cache::download;
+#[cfg(all(feature = "async", unix))]
+use crate::cache::{open_sparse, SparseOpenResult};
+use crate::cloud::{CloudOptions, CloudType};
+use crate::context::Obstacle;
 use crate::err::ObstacleError;
+#[cfg(all(feature = "lazy", target_os = "linux"))]
+use crate::lazy::{is_lazy_mmap, is_userfaultfd_available, LazyMapping};
+use crate::runtime::block_on;
+#[cfg(all(feature = "async", unix))]
+use crate::sparse::SparseFile;
+#[cfg(unix)]
+pub use memmap2::Advice;
 use memmap2::{self, MmapAsRawDesc, MmapOptions};
 use std::fmt::Debug;
 use std::fs::File;
-use std::io;
+use std::io::{self, ErrorKind};
 use std::ops::Deref;
+#[cfg(unix)]
+use std::ops::Range;
 use std::str::FromStr;
-use tokio;
+#[cfg(all(feature = "async", unix))]
+use std::sync::Arc;
+use url::Url;
 
 /// Wrapped for the memmap2::Mmap.
-pub struct Mmap(memmap2::Mmap);
+pub struct Mmap {
+    /// When present the pages of `inner` are downloaded on first access, dropped before `inner`.
+    #[cfg(all(feature = "lazy", target_os = "linux"))]
+    lazy: Option<LazyMapping>,
+    inner: memmap2::Mmap,
+    /// When present only the ranges passed to `advise()` are downloaded.
+    #[cfg(all(feature = "async", unix))]
+    sparse: Option<Arc<SparseFile>>,
+}
+
+/// True when the url is a local file opened in place, without going through the cache.
+fn _is_local_file(_obstacle: &Obstacle, url: &str) -> bool {
+    #[cfg(feature = "async")]
+    if _obstacle.cache_config().local_copy() {
+        return false;
+    }
+    matches!(CloudType::from_str(url), Ok(CloudType::File))
+}
 
-async fn _open_url(url: &str) -> Result<Option<File>, ObstacleError> {
+/// Open the file of a `file://` url in place.
+fn _open_local_file(url: &str) -> Result<Option<File>, ObstacleError> {
+    let path = Url::parse(url)?
+        .to_file_path()
+        .map_err(|_| ObstacleError::new(format!("cannot convert {} to a local path", url)))?;
+    match File::open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Open the url with the configuration and the stores of the instance.
+pub(crate) async fn open(
+    obstacle: &Obstacle,
+    url: &str,
+    _cloud_options: Option<&CloudOptions>,
+) -> Result<Option<File>, ObstacleError> {
+    if _is_local_file(obstacle, url) {
+        return _open_local_file(url);
+    }
     match CloudType::from_str(url) {
         Ok(_cloud_type) => {
             #[cfg(feature = "async")]
             {
-                Ok(download_file(url).await?)
+                let policy = obstacle.cache_config().policy();
+                Ok(download(obstacle, url, policy, _cloud_options).await?)
             }
             #[cfg(not(feature = "async"))]
             {
@@ -33,26 +85,195 @@ async fn _open_url(url: &str) -> Result<Option<File>, ObstacleError> {
     }
 }
 
-#[tokio::main]
 /// Create a file map from a local or cloud path.
-pub async fn open_url<S: AsRef<str>>(url: S) -> Result<Option<File>, ObstacleError> {
-    _open_url(url.as_ref()).await
+pub fn open_url<S: AsRef<str>>(url: S) -> Result<Option<File>, ObstacleError> {
+    block_on(open(Obstacle::global(), url.as_ref(), None))
+}
+
+/// Create a file map from a local or cloud path, see `open_url()`.
+pub async fn open_url_async<S: AsRef<str>>(url: S) -> Result<Option<File>, ObstacleError> {
+    open(Obstacle::global(), url.as_ref(), None).await
+}
+
+/// Create a file map from a local or cloud path, using the given options instead of the configured ones.
+pub fn open_url_with_options<S: AsRef<str>>(
+    url: S,
+    cloud_options: &CloudOptions,
+) -> Result<Option<File>, ObstacleError> {
+    block_on(open(Obstacle::global(), url.as_ref(), Some(cloud_options)))
+}
+
+/// Create a file map from a local or cloud path, see `open_url_with_options()`.
+pub async fn open_url_with_options_async<S: AsRef<str>>(
+    url: S,
+    cloud_options: &CloudOptions,
+) -> Result<Option<File>, ObstacleError> {
+    open(Obstacle::global(), url.as_ref(), Some(cloud_options)).await
 }
 
 impl Mmap {
     /// Create a memory map from an object with the MmapAsRawDesc trait.
     pub unsafe fn map<T: MmapAsRawDesc + Debug>(file: T) -> Result<Mmap, io::Error> {
-        Ok(Mmap(MmapOptions::new().map(file)?))
+        Ok(Mmap {
+            #[cfg(all(feature = "lazy", target_os = "linux"))]
+            lazy: None,
+            inner: MmapOptions::new().map(file)?,
+            #[cfg(all(feature = "async", unix))]
+            sparse: None,
+        })
+    }
+
+    /// Create a memory map from a local or cloud url.
+    ///
+    /// Cloud objects are downloaded to the local cache before being mapped, unless lazy memory maps
+    /// have been enabled with `set_lazy_mmap()` and userfaultfd is available. The files of `file://` urls
+    /// are mapped in place, a file truncated while mapped faults on access, use
+    /// `CacheConfig::with_local_copy()` to map a cached copy instead.
+    pub fn from_url(url: &str) -> Result<Option<Mmap>, ObstacleError> {
+        block_on(Self::open(Obstacle::global(), url, None))
+    }
+
+    /// Create a memory map from a local or cloud url, see `from_url()`.
+    pub async fn from_url_async(url: &str) -> Result<Option<Mmap>, ObstacleError> {
+        Self::open(Obstacle::global(), url, None).await
+    }
+
+    /// Create a memory map from a local or cloud url, using the given options instead of the configured ones.
+    pub fn from_url_with_options(
+        url: &str,
+        cloud_options: &CloudOptions,
+    ) -> Result<Option<Mmap>, ObstacleError> {
+        block_on(Self::open(Obstacle::global(), url, Some(cloud_options)))
+    }
+
+    /// Create a memory map from a local or cloud url, see `from_url_with_options()`.
+    pub async fn from_url_with_options_async(
+        url: &str,
+        cloud_options: &CloudOptions,
+    ) -> Result<Option<Mmap>, ObstacleError> {
+        Self::open(Obstacle::global(), url, Some(cloud_options)).await
     }
 
-    #[tokio::main]
-    pub async fn from_url(url: &str) -> Result<Option<Mmap>, ObstacleError> {
-        _open_url(url)
+    /// Create a memory map with the configuration and the stores of the instance.
+    pub(crate) async fn open(
+        obstacle: &Obstacle,
+        url: &str,
+        cloud_options: Option<&CloudOptions>,
+    ) -> Result<Option<Mmap>, ObstacleError> {
+        #[cfg(all(feature = "lazy", target_os = "linux"))]
+        if is_lazy_mmap()
+            && is_userfaultfd_available()
+            && CloudType::from_str(url).is_ok()
+            && !_is_local_file(obstacle, url)
+        {
+            return Self::_lazy_from_url(obstacle, url, cloud_options).await;
+        }
+        open(obstacle, url, cloud_options)
             .await?
             .as_ref()
             .map(|file| unsafe { Ok(Self::map(file)?) })
             .transpose()
     }
+
+    /// Create a memory map from a cloud url without downloading the content.
+    ///
+    /// The ranges that will be accessed must be requested through `advise()` before reading them,
+    /// the other ranges read as zeros.
+    #[cfg(all(feature = "async", unix))]
+    pub fn from_url_sparse(url: &str) -> Result<Option<Mmap>, ObstacleError> {
+        block_on(Self::from_url_sparse_async(url))
+    }
+
+    /// Create a memory map from a cloud url without downloading the content, see `from_url_sparse()`.
+    #[cfg(all(feature = "async", unix))]
+    pub async fn from_url_sparse_async(url: &str) -> Result<Option<Mmap>, ObstacleError> {
+        Self::open_sparse(Obstacle::global(), url).await
+    }
+
+    /// Create a sparse memory map with the configuration and the stores of the instance.
+    #[cfg(all(feature = "async", unix))]
+    pub(crate) async fn open_sparse(
+        obstacle: &Obstacle,
+        url: &str,
+    ) -> Result<Option<Mmap>, ObstacleError> {
+        if _is_local_file(obstacle, url) {
+            return _open_local_file(url)?
+                .map(|file| unsafe { Ok(Self::map(&file)?) })
+                .transpose();
+        }
+        match open_sparse(obstacle, url, None).await? {
+            None => Ok(None),
+            Some(SparseOpenResult::Cached(file)) => Ok(Some(unsafe { Self::map(&file)? })),
+            Some(SparseOpenResult::Sparse(sparse)) => {
+                let inner = unsafe { MmapOptions::new().map(sparse.file())? };
+                Ok(Some(Mmap {
+                    #[cfg(all(feature = "lazy", target_os = "linux"))]
+                    lazy: None,
+                    inner,
+                    sparse: Some(Arc::from(sparse)),
+                }))
+            }
+        }
+    }
+
+    /// Create an anonymous memory map where the pages are fetched from the cloud on first access.
+    #[cfg(all(feature = "lazy", target_os = "linux"))]
+    async fn _lazy_from_url(
+        obstacle: &Obstacle,
+        url: &str,
+        cloud_options: Option<&CloudOptions>,
+    ) -> Result<Option<Mmap>, ObstacleError> {
+        match open_sparse(obstacle, url, cloud_options).await? {
+            None => Ok(None),
+            Some(SparseOpenResult::Cached(file)) => Ok(Some(unsafe { Self::map(&file)? })),
+            Some(SparseOpenResult::Sparse(sparse)) if sparse.len() == 0 => {
+                Ok(Some(unsafe { Self::map(sparse.file())? }))
+            }
+            Some(SparseOpenResult::Sparse(sparse)) => {
+                let sparse: Arc<SparseFile> = Arc::from(sparse);
+                let inner = MmapOptions::new()
+                    .len(sparse.len())
+                    .map_anon()?
+                    .make_read_only()?;
+                let lazy = LazyMapping::new(inner.as_ptr(), inner.len(), sparse.clone())?;
+                Ok(Some(Mmap {
+                    lazy: Some(lazy),
+                    inner,
+                    sparse: Some(sparse),
+                }))
+            }
+        }
+    }
+
+    /// Advise how the given range will be accessed, for sparse maps the range is downloaded first.
+    ///
+    /// For lazy maps, returns the error of a block that could not be fetched since the last call, see `set_lazy_mmap()`.
+    #[cfg(unix)]
+    pub fn advise(&self, range: Range<usize>, advice: Advice) -> Result<(), ObstacleError> {
+        block_on(self.advise_async(range, advice))
+    }
+
+    /// Advise how the given range will be accessed, see `advise()`.
+    #[cfg(unix)]
+    pub async fn advise_async(
+        &self,
+        range: Range<usize>,
+        advice: Advice,
+    ) -> Result<(), ObstacleError> {
+        let range = range.start.min(self.inner.len())..range.end.min(self.inner.len());
+        #[cfg(all(feature = "lazy", target_os = "linux"))]
+        if let Some(err) = self.lazy.as_ref().and_then(LazyMapping::take_error) {
+            return Err(err);
+        }
+        #[cfg(feature = "async")]
+        if let Some(sparse) = &self.sparse {
+            sparse.fetch(range.clone()).await?;
+        }
+        if !range.is_empty() {
+            self.inner.advise_range(advice, range.start, range.len())?;
+        }
+        Ok(())
+    }
 }
 
 impl Deref for Mmap {
@@ -60,13 +281,13 @@ impl Deref for Mmap {
 
     #[inline]
     fn deref(&self) -> &[u8] {
-        self.0.deref()
+        self.inner.deref()
     }
 }
 
 impl AsRef<[u8]> for Mmap {
     #[inline]
     fn as_ref(&self) -> &[u8] {
-        self.0.deref()
+        self.inner.deref()
     }
 }
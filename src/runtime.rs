@@ -0,0 +1,62 @@
+//! Run the async implementation of the blocking API.
+//!
+//! The blocking functions share a single multi-threaded runtime, created on first use, or the runtime set with
+//! `set_runtime_handle()`. They can be called from inside a tokio runtime: on a multi-threaded runtime the
+//! calling worker is handed over with `block_in_place`, on a current thread runtime the future is driven
+//! from another thread. Async services should prefer the `_async` variants.
+
+use crate::err::ObstacleError;
+use std::future::Future;
+use std::sync::{Mutex, RwLock};
+use tokio::runtime::{Builder, Handle, Runtime, RuntimeFlavor};
+use tokio::task::block_in_place;
+
+static RUNTIME: Mutex<Option<Runtime>> = Mutex::new(None);
+
+static HANDLE: RwLock<Option<Handle>> = RwLock::new(None);
+
+/// Run the blocking API on the given runtime instead of the runtime created by the crate.
+///
+/// The runtime must be multi-threaded, a current thread runtime only makes progress inside its own `block_on()`.
+pub fn set_runtime_handle(handle: Handle) {
+    *HANDLE.write().unwrap() = Some(handle);
+}
+
+fn _handle() -> Result<Handle, ObstacleError> {
+    if let Some(handle) = HANDLE.read().unwrap().as_ref() {
+        return Ok(handle.clone());
+    }
+    let mut runtime = RUNTIME.lock().unwrap();
+    if runtime.is_none() {
+        *runtime = Some(
+            Builder::new_multi_thread()
+                .enable_all()
+                .thread_name("obstacle")
+                .build()?,
+        );
+    }
+    Ok(runtime.as_ref().unwrap().handle().clone())
+}
+
+/// Block the current thread until the future completes.
+pub fn block_on<F, T>(future: F) -> Result<T, ObstacleError>
+where
+    F: Future<Output = Result<T, ObstacleError>> + Send,
+    T: Send,
+{
+    match Handle::try_current() {
+        Err(_) => _handle()?.block_on(future),
+        Ok(current) if current.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            block_in_place(|| current.block_on(future))
+        }
+        Ok(_) => {
+            let handle = _handle()?;
+            std::thread::scope(
+                |scope| match scope.spawn(move || handle.block_on(future)).join() {
+                    Ok(result) => result,
+                    Err(panic) => std::panic::resume_unwind(panic),
+                },
+            )
+        }
+    }
+}
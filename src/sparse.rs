@@ -0,0 +1,364 @@
+//! Sparse downloads, only the byte ranges requested through `advise()` are fetched from the cloud.
+//!
+//! The local file is created with the full size of the cloud object, the ranges that were not fetched
+//! are left as holes by the file system. Once all the ranges have been fetched the file is promoted
+//! to a regular `content_<e-tag>` file in the cache.
+//!
+//! Downloads are done in fixed-size blocks. The blocks already fetched are recorded in a `blocks_<e-tag>`
+//! bitmap next to the sparse file, this allows partial downloads to be reused by later processes.
+//!
+//! The blocks are only fetched from the version of the object the sparse file was created for. Once the object
+//! changes in the cloud, the sparse file and its bitmap are removed and the fetches fail.
+
+use crate::cache_config::CacheConfig;
+use crate::cache_key::version_range;
+use crate::cache_path::cache_file_name;
+use crate::err::{obstinate_err, ObstacleError};
+use crate::eviction::{enforce_budget, InUse};
+use crate::lock::CacheLock;
+use futures::future::try_join_all;
+use log::{debug, warn};
+use object_store::path::Path as ObjectStorePath;
+use object_store::{ObjectMeta, ObjectStore};
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::{remove_file, rename};
+use uuid::Uuid;
+
+/// The size of the blocks downloaded for sparse files.
+pub const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Records which blocks of a sparse file have been downloaded.
+///
+/// The on-disk format is the block size and the object size as little endian u64, followed by the bits.
+#[derive(Debug, PartialEq)]
+pub struct BlockBitmap {
+    block_size: usize,
+    size: usize,
+    bits: Vec<u8>,
+}
+
+impl BlockBitmap {
+    /// Create a bitmap where no block has been fetched.
+    pub fn new(block_size: usize, size: usize) -> Self {
+        let block_count = size.div_ceil(block_size);
+        BlockBitmap {
+            block_size,
+            size,
+            bits: vec![0; block_count.div_ceil(8)],
+        }
+    }
+
+    /// Load the bitmap saved at `path`, a missing or mismatched bitmap results in an empty one.
+    pub fn load(path: &Path, block_size: usize, size: usize) -> Result<Self, ObstacleError> {
+        let empty = Self::new(block_size, size);
+        let buffer = match fs::read(path) {
+            Ok(buffer) => buffer,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(empty),
+            Err(err) => return Err(err.into()),
+        };
+        if buffer.len() != 16 + empty.bits.len()
+            || buffer[0..8] != (block_size as u64).to_le_bytes()
+            || buffer[8..16] != (size as u64).to_le_bytes()
+        {
+            debug!("ignoring mismatched block bitmap {}", path.display());
+            return Ok(empty);
+        }
+        Ok(BlockBitmap {
+            block_size,
+            size,
+            bits: buffer[16..].to_vec(),
+        })
+    }
+
+    /// Save the bitmap at `path`, the file is replaced atomically.
+    pub fn save(&self, path: &Path) -> Result<(), ObstacleError> {
+        let mut buffer = Vec::with_capacity(16 + self.bits.len());
+        buffer.extend_from_slice(&(self.block_size as u64).to_le_bytes());
+        buffer.extend_from_slice(&(self.size as u64).to_le_bytes());
+        buffer.extend_from_slice(&self.bits);
+        let tempfile = path.with_file_name(format!("temp_{}", Uuid::new_v4()));
+        fs::write(&tempfile, buffer)?;
+        fs::rename(&tempfile, path)?;
+        Ok(())
+    }
+
+    fn block_count(&self) -> usize {
+        self.size.div_ceil(self.block_size)
+    }
+
+    fn is_set(&self, block: usize) -> bool {
+        self.bits[block / 8] & (1 << (block % 8)) != 0
+    }
+
+    fn set(&mut self, block: usize) {
+        self.bits[block / 8] |= 1 << (block % 8);
+    }
+
+    /// Mark the blocks fetched in `other` as fetched.
+    pub fn merge(&mut self, other: &BlockBitmap) {
+        for (bits, other_bits) in self.bits.iter_mut().zip(other.bits.iter()) {
+            *bits |= other_bits;
+        }
+    }
+
+    /// Return true when all the blocks have been fetched.
+    pub fn is_complete(&self) -> bool {
+        (0..self.block_count()).all(|block| self.is_set(block))
+    }
+
+    /// Return the byte ranges of the blocks overlapping `range` that have not been fetched.
+    /// Consecutive missing blocks are merged in a single range.
+    pub fn missing_ranges(&self, range: &Range<usize>) -> Vec<Range<usize>> {
+        let mut missing: Vec<Range<usize>> = Vec::new();
+        if range.is_empty() {
+            return missing;
+        }
+        let first = range.start / self.block_size;
+        let last = range.end.min(self.size).div_ceil(self.block_size);
+        for block in first..last {
+            if self.is_set(block) {
+                continue;
+            }
+            let start = block * self.block_size;
+            let end = (start + self.block_size).min(self.size);
+            match missing.last_mut() {
+                Some(previous) if previous.end == start => previous.end = end,
+                _ => missing.push(start..end),
+            }
+        }
+        missing
+    }
+
+    /// Mark the blocks fully covered by `range` as fetched.
+    pub fn insert(&mut self, range: &Range<usize>) {
+        let first = range.start.div_ceil(self.block_size);
+        let last = if range.end >= self.size {
+            self.block_count()
+        } else {
+            range.end / self.block_size
+        };
+        for block in first..last {
+            self.set(block);
+        }
+    }
+}
+
+/// A local file backing a cloud object, only some ranges of the file have been downloaded.
+pub struct SparseFile {
+    object_store: Arc<dyn ObjectStore>,
+    os_path: ObjectStorePath,
+    file: File,
+    size: usize,
+    /// The version of the object the blocks are fetched from.
+    meta: ObjectMeta,
+    /// The location of the sparse file in the cache.
+    sparse_path: PathBuf,
+    /// The location of the file in the cache once all the ranges have been fetched.
+    content_path: PathBuf,
+    /// The location of the persisted block bitmap.
+    blocks_path: PathBuf,
+    /// The blocks already fetched.
+    fetched: Mutex<BlockBitmap>,
+    /// The configuration of the cache the file belongs to.
+    config: CacheConfig,
+    /// The sparse file is not evicted while it is being fetched.
+    _in_use: InUse,
+}
+
+impl SparseFile {
+    /// Use the sparse file of the version of the object with the key, saved in the directory of the object.
+    ///
+    /// The metadata describes the version of the object the key was derived from.
+    pub fn new(
+        object_store: Arc<dyn ObjectStore>,
+        meta: ObjectMeta,
+        file: File,
+        local_base: &Path,
+        key: &str,
+        config: CacheConfig,
+    ) -> Result<Self, ObstacleError> {
+        let size = meta.size;
+        let blocks_path = local_base.join(cache_file_name("blocks", key));
+        let fetched = BlockBitmap::load(&blocks_path, BLOCK_SIZE, size)?;
+        Ok(SparseFile {
+            object_store,
+            os_path: meta.location.clone(),
+            file,
+            size,
+            meta,
+            sparse_path: local_base.join(cache_file_name("sparse", key)),
+            content_path: local_base.join(cache_file_name("content", key)),
+            blocks_path,
+            fetched: Mutex::new(fetched),
+            config,
+            _in_use: InUse::new(local_base),
+        })
+    }
+
+    /// The size of the cloud object.
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// The local file, to be used for the memory map.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Download the blocks overlapping the range that have not been fetched yet.
+    pub async fn fetch(&self, range: Range<usize>) -> Result<(), ObstacleError> {
+        let range = range.start.min(self.size)..range.end.min(self.size);
+        let missing = self.fetched.lock().unwrap().missing_ranges(&range);
+        if missing.is_empty() {
+            return Ok(());
+        }
+        debug!("fetching {:?} from {}", missing, self.os_path);
+        // Only the version the sparse file was created for is fetched.
+        let requests = missing.iter().map(|range| async move {
+            let get_options = version_range(&self.meta, range.clone());
+            let result = self
+                .object_store
+                .get_opts(&self.os_path, get_options)
+                .await?;
+            result.bytes().await
+        });
+        let buffers = match try_join_all(requests).await {
+            Ok(buffers) => buffers,
+            Err(object_store::Error::Precondition { .. }) => {
+                self._discard().await?;
+                return obstinate_err(format!(
+                    "{} changed in the cloud since it was opened",
+                    self.os_path
+                ));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        for (missing_range, bytes) in missing.iter().zip(buffers.iter()) {
+            if bytes.len() != missing_range.len() {
+                return obstinate_err(format!(
+                    "expected {} bytes for range {:?} of {}, got {}",
+                    missing_range.len(),
+                    missing_range,
+                    self.os_path,
+                    bytes.len()
+                ));
+            }
+            self.file.write_all_at(bytes, missing_range.start as u64)?;
+        }
+
+        // Make sure the content is on disk before recording it in the bitmap.
+        self.file.sync_data()?;
+
+        // Other processes may have fetched blocks of the same file, merge them before saving.
+        let _lock = CacheLock::exclusive(self.sparse_path.parent().unwrap()).await?;
+        if self.content_path.exists() {
+            debug!(
+                "already promoted by another process {}",
+                self.content_path.display()
+            );
+            return Ok(());
+        }
+        let saved = BlockBitmap::load(&self.blocks_path, BLOCK_SIZE, self.size)?;
+        let is_complete = {
+            let mut fetched = self.fetched.lock().unwrap();
+            for missing_range in missing.iter() {
+                fetched.insert(missing_range);
+            }
+            fetched.merge(&saved);
+            fetched.save(&self.blocks_path)?;
+            fetched.is_complete()
+        };
+        let fetched_size = missing.iter().map(|range| range.len() as u64).sum();
+        if is_complete {
+            debug!(
+                "all blocks fetched, promoting {}",
+                self.sparse_path.display()
+            );
+            rename(&self.sparse_path, &self.content_path).await?;
+            remove_file(&self.blocks_path).await?;
+            enforce_budget(&self.config, &self.content_path, fetched_size, 0)?;
+        } else {
+            enforce_budget(&self.config, &self.sparse_path, fetched_size, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the sparse file and its bitmap, their blocks belong to a version of the object that is gone.
+    async fn _discard(&self) -> Result<(), ObstacleError> {
+        let _lock = CacheLock::exclusive(self.sparse_path.parent().unwrap()).await?;
+        warn!("removing outdated {}", self.sparse_path.display());
+        for path in [&self.sparse_path, &self.blocks_path] {
+            match remove_file(path).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_missing_ranges() {
+        let mut bitmap = BlockBitmap::new(10, 45);
+        assert_eq!(bitmap.missing_ranges(&(0..45)), vec![0..45]);
+        // Ranges are extended to the block boundaries.
+        assert_eq!(bitmap.missing_ranges(&(12..25)), vec![10..30]);
+        bitmap.insert(&(10..20));
+        bitmap.insert(&(30..40));
+        assert_eq!(bitmap.missing_ranges(&(0..45)), vec![0..10, 20..30, 40..45]);
+        assert_eq!(bitmap.missing_ranges(&(12..18)), Vec::<Range<usize>>::new());
+        assert!(bitmap.missing_ranges(&(5..5)).is_empty());
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut bitmap = BlockBitmap::new(10, 45);
+        // Partial blocks are not marked as fetched.
+        bitmap.insert(&(5..15));
+        assert_eq!(bitmap.missing_ranges(&(0..20)), vec![0..20]);
+        // The last block is shorter than the block size.
+        bitmap.insert(&(40..45));
+        assert_eq!(bitmap.missing_ranges(&(30..45)), vec![30..40]);
+        bitmap.insert(&(0..40));
+        assert!(bitmap.is_complete());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut bitmap = BlockBitmap::new(10, 45);
+        bitmap.insert(&(0..10));
+        let mut other = BlockBitmap::new(10, 45);
+        other.insert(&(20..45));
+        bitmap.merge(&other);
+        assert_eq!(bitmap.missing_ranges(&(0..45)), vec![10..20]);
+    }
+
+    #[test]
+    fn test_save_load() {
+        let path = std::env::temp_dir().join(format!("blocks_{}", Uuid::new_v4()));
+        let mut bitmap = BlockBitmap::new(10, 95);
+        bitmap.insert(&(20..50));
+        bitmap.insert(&(90..95));
+        bitmap.save(&path).unwrap();
+        assert_eq!(BlockBitmap::load(&path, 10, 95).unwrap(), bitmap);
+        // A bitmap saved for another object size is ignored.
+        assert_eq!(
+            BlockBitmap::load(&path, 10, 100).unwrap(),
+            BlockBitmap::new(10, 100)
+        );
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            BlockBitmap::load(&path, 10, 95).unwrap(),
+            BlockBitmap::new(10, 95)
+        );
+    }
+}